@@ -4,19 +4,180 @@
 extern crate clap;
 extern crate toby;
 
-use clap::{AppSettings, SubCommand};
-use toby::cli::toby::{gen_secret, telegram_setup};
+use clap::{AppSettings, Arg, ArgMatches, SubCommand};
+use toby::cli::toby::{gen_secret, hash_secret, prune, run, telegram_setup};
+use toby::cli::{project, token};
+
+fn name_arg<'a, 'b>(help: &'a str) -> Arg<'a, 'b> {
+    Arg::with_name("name").help(help).required(true).index(1)
+}
+
+fn token_command(matches: &ArgMatches) {
+    match matches.subcommand() {
+        ("add", Some(matches)) => {
+            let access: Vec<_> = matches.values_of("access").unwrap().collect();
+            let permissions: Option<Vec<_>> =
+                matches.values_of("permissions").map(Iterator::collect);
+
+            token::add(
+                matches.value_of("name").unwrap(),
+                &access,
+                permissions.as_ref().map(Vec::as_slice),
+            )
+        }
+        ("list", _) => token::list(),
+        ("rotate", Some(matches)) => token::rotate(matches.value_of("name").unwrap()),
+        ("revoke", Some(matches)) => token::revoke(matches.value_of("name").unwrap()),
+        _ => unreachable!(),
+    }
+}
+
+fn project_command(matches: &ArgMatches) {
+    match matches.subcommand() {
+        ("new", Some(matches)) => {
+            project::new(matches.value_of("name").unwrap(), matches.value_of("token"))
+        }
+        ("list", _) => project::list(),
+        ("show", Some(matches)) => project::show(matches.value_of("name").unwrap()),
+        _ => unreachable!(),
+    }
+}
 
 fn main() {
     let matches = toby::clap_app!()
         .subcommand(SubCommand::with_name("gen-secret").about("Generates a new, random secret"))
+        .subcommand(
+            SubCommand::with_name("hash-secret")
+                .about("Hashes a secret for use in tokens.toml")
+                .arg(
+                    Arg::with_name("secret")
+                        .help("The secret to hash (read from stdin if omitted)")
+                        .index(1),
+                ),
+        )
         .subcommand(SubCommand::with_name("telegram-setup").about("Sets up the telegram bot"))
+        .subcommand(
+            SubCommand::with_name("token")
+                .about("Manages the tokens in tokens.toml")
+                .setting(AppSettings::SubcommandRequired)
+                .subcommand(
+                    SubCommand::with_name("add")
+                        .about("Adds a new token and prints its secret")
+                        .arg(name_arg("Name of the token"))
+                        .arg(
+                            Arg::with_name("access")
+                                .long("access")
+                                .help("Projects (or patterns) the token has access to")
+                                .takes_value(true)
+                                .use_delimiter(true)
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::with_name("permissions")
+                                .long("permissions")
+                                .help("Permissions of the token (defaults to trigger)")
+                                .takes_value(true)
+                                .use_delimiter(true)
+                                .possible_values(&[
+                                    "trigger",
+                                    "read_status",
+                                    "read_logs",
+                                    "read_artifacts",
+                                    "cancel",
+                                    "approve",
+                                    "prioritize",
+                                ]),
+                        ),
+                )
+                .subcommand(SubCommand::with_name("list").about("Lists all tokens"))
+                .subcommand(
+                    SubCommand::with_name("rotate")
+                        .about("Generates a new secret for a token and prints it")
+                        .arg(name_arg("Name of the token")),
+                )
+                .subcommand(
+                    SubCommand::with_name("revoke")
+                        .about("Removes a token")
+                        .arg(name_arg("Name of the token")),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("project")
+                .about("Creates and inspects projects")
+                .setting(AppSettings::SubcommandRequired)
+                .subcommand(
+                    SubCommand::with_name("new")
+                        .about("Creates a starter config for a new project")
+                        .arg(name_arg("Name of the project"))
+                        .arg(
+                            Arg::with_name("token")
+                                .long("token")
+                                .help("Grants an existing token access to the project")
+                                .takes_value(true),
+                        ),
+                )
+                .subcommand(SubCommand::with_name("list").about("Lists all projects"))
+                .subcommand(
+                    SubCommand::with_name("show")
+                        .about("Prints the project's config as seen by tobyd")
+                        .arg(name_arg("Name of the project")),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("run")
+                .about("Runs a job for a project in the foreground")
+                .arg(name_arg("Name of the project"))
+                .arg(
+                    Arg::with_name("ref")
+                        .long("ref")
+                        .help("The ref to check out (overrides the project's source ref)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("commit")
+                        .long("commit")
+                        .help("The commit to check out")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("param")
+                        .long("param")
+                        .value_name("NAME=VALUE")
+                        .help("Passes a parameter to the job (can be used multiple times)")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .help("Only prints the environment and commands"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("prune")
+                .about("Removes old jobs according to the configured retention policies"),
+        )
         .settings(&[AppSettings::SubcommandRequired])
         .get_matches();
 
-    match matches.subcommand_name().unwrap() {
-        "gen-secret" => gen_secret(),
-        "telegram-setup" => telegram_setup(),
+    match matches.subcommand() {
+        ("gen-secret", _) => gen_secret(),
+        ("hash-secret", Some(matches)) => hash_secret(matches.value_of("secret")),
+        ("telegram-setup", _) => telegram_setup(),
+        ("token", Some(matches)) => token_command(matches),
+        ("project", Some(matches)) => project_command(matches),
+        ("run", Some(matches)) => run(
+            matches.value_of("name").unwrap(),
+            matches.value_of("ref"),
+            matches.value_of("commit"),
+            &matches
+                .values_of("param")
+                .map(Iterator::collect::<Vec<_>>)
+                .unwrap_or_default(),
+            matches.is_present("dry-run"),
+        ),
+        ("prune", _) => prune(),
         _ => unreachable!(),
     }
 }
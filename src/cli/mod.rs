@@ -1,2 +1,4 @@
+pub mod project;
+pub mod token;
 pub mod toby;
 pub mod tobyd;
@@ -0,0 +1,181 @@
+use crate::config::{
+    edit_tokens, find_project_config, get_config, get_projects, get_tokens, EditError,
+};
+use crate::fs::last_job_id;
+use crate::unwrap_err;
+use crate::worker::read_job_archive;
+use glob::Pattern;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::process;
+use toml;
+use toml_edit::{value, Value};
+
+const PROJECT_TEMPLATE: &str = r#"# Configuration for the project "{name}".
+# See https://github.com/bash/toby/blob/master/docs/config.md#projects
+
+# Additional environment variables that are passed to the scripts.
+# [environment]
+# KEY = "value"
+
+# Scripts are executed in order. A failing script aborts the job,
+# unless `allow_failure = true` is set.
+[[scripts]]
+command = ["echo", "Hello from {name}"]
+"#;
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+///
+/// Adds the project to the token's access list.
+/// Returns `false` if the token already has access to the project (e.g. through a pattern).
+///
+fn grant_access(token: &str, project_name: &str) -> Result<bool, EditError> {
+    let mut granted = false;
+
+    edit_tokens(|document| {
+        if !document.as_table().contains_table(token) {
+            return Err(EditError::TokenNotFound(token.into()));
+        }
+
+        let mut access: Vec<String> = document[token]["access"]
+            .as_array()
+            .map(|access| {
+                access
+                    .iter()
+                    .filter_map(|value| value.as_str().map(String::from))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let has_access = access.iter().any(|pattern| {
+            Pattern::new(pattern)
+                .map(|pattern| pattern.matches(project_name))
+                .unwrap_or(false)
+        });
+
+        if has_access {
+            return Ok(());
+        }
+
+        access.push(project_name.into());
+
+        document[token]["access"] = value(access.iter().map(String::as_str).collect::<Value>());
+        granted = true;
+
+        Ok(())
+    })?;
+
+    Ok(granted)
+}
+
+pub fn new(name: &str, token: Option<&str>) {
+    if !is_valid_name(name) {
+        eprintln!("Project names may only contain alphanumeric characters, '-' and '_'");
+        process::exit(1);
+    }
+
+    let path = find_project_config(name);
+
+    if path.exists() {
+        eprintln!("Project {} already exists", name);
+        process::exit(1);
+    }
+
+    if let Some(token) = token {
+        if !unwrap_err!(get_tokens()).contains_key(token) {
+            eprintln!("Token {} does not exist", token);
+            process::exit(1);
+        }
+    }
+
+    let mut file = unwrap_err!(OpenOptions::new().write(true).create_new(true).open(&path));
+
+    unwrap_err!(file.write_all(PROJECT_TEMPLATE.replace("{name}", name).as_bytes()));
+
+    println!("Project {} created in {}", name, path.to_string_lossy());
+
+    if let Some(token) = token {
+        if unwrap_err!(grant_access(token, name)) {
+            println!("Token {} now has access to {}", token, name);
+        } else {
+            println!("Token {} already has access to {}", token, name);
+        }
+    }
+}
+
+fn last_result(name: &str) -> (String, String) {
+    let last_id = match unwrap_err!(last_job_id(name)) {
+        Some(id) => id,
+        None => return ("-".into(), "-".into()),
+    };
+
+    let result = match unwrap_err!(read_job_archive(name, last_id)) {
+        Some(ref archive) if archive.cancelled => "cancelled",
+        Some(ref archive) if archive.is_rejected() => "rejected",
+        Some(ref archive) if archive.interrupted => "interrupted",
+        Some(ref archive) if archive.successful => "successful",
+        Some(..) => "failed",
+        None => "pending",
+    };
+
+    (format!("#{}", last_id), result.into())
+}
+
+pub fn list() {
+    let projects = unwrap_err!(get_projects());
+    let mut names: Vec<_> = projects.keys().collect();
+
+    names.sort();
+
+    let width = names
+        .iter()
+        .map(|name| name.len())
+        .max()
+        .unwrap_or(0)
+        .max(7);
+
+    println!(
+        "{:width$}  {:>7}  {:>8}  {}",
+        "PROJECT",
+        "SCRIPTS",
+        "LAST JOB",
+        "LAST RESULT",
+        width = width
+    );
+
+    for name in names {
+        let (last_job, result) = last_result(name);
+
+        println!(
+            "{:width$}  {:>7}  {:>8}  {}",
+            name,
+            projects[name].scripts.len(),
+            last_job,
+            result,
+            width = width
+        );
+    }
+}
+
+pub fn show(name: &str) {
+    let config = unwrap_err!(get_config());
+
+    let project = match config.projects.get(name) {
+        Some(project) => project,
+        None => {
+            eprintln!("Project {} does not exist", name);
+            process::exit(1);
+        }
+    };
+
+    let resolved = toml::Value::try_from(project).and_then(|value| toml::to_string(&value));
+
+    println!("# {}", find_project_config(name).to_string_lossy());
+    print!("{}", unwrap_err!(resolved));
+}
@@ -1,8 +1,15 @@
-use crate::config::get_config;
-use crate::fs::write_telegram_chat_id;
+use crate::config::{get_config, hash_secret as hash};
+use crate::fs::{last_job_id, next_job_id, write_telegram_chat_id};
+use crate::retention::prune_all;
 use crate::telegram::{Api, ParseMode, SendMessageParams};
 use crate::unwrap_err;
+use crate::worker::{
+    dry_run_job, is_valid_parameter_name, run_job_foreground, Job, JobTrigger, Parameters, Revision,
+};
 use nanoid;
+use std::env;
+use std::io::{self, BufRead};
+use std::process;
 use std::thread;
 use std::time::Duration;
 
@@ -10,6 +17,120 @@ pub fn gen_secret() {
     println!("{}", nanoid::simple());
 }
 
+pub fn hash_secret(secret: Option<&str>) {
+    let secret = match secret {
+        Some(secret) => secret.to_string(),
+        None => {
+            let stdin = io::stdin();
+            let mut line = String::new();
+
+            unwrap_err!(stdin.lock().read_line(&mut line));
+
+            line.trim().to_string()
+        }
+    };
+
+    if secret.is_empty() {
+        eprintln!("Secret must not be empty");
+        process::exit(1);
+    }
+
+    println!("{}", hash(&secret));
+}
+
+pub fn prune() {
+    let config = unwrap_err!(get_config());
+    let results = prune_all(&config);
+
+    if results.is_empty() {
+        println!("No retention policy configured");
+        return;
+    }
+
+    let mut failed = false;
+
+    for (name, result) in results {
+        match result {
+            Ok(report) => println!("{}: {}", name, report),
+            Err(err) => {
+                eprintln!("{}: Unable to prune jobs: {}", name, err);
+                failed = true;
+            }
+        }
+    }
+
+    if failed {
+        process::exit(1);
+    }
+}
+
+pub fn run(
+    project_name: &str,
+    git_ref: Option<&str>,
+    commit: Option<&str>,
+    params: &[&str],
+    dry_run: bool,
+) {
+    let config = unwrap_err!(get_config());
+    let revision = Revision {
+        git_ref: git_ref.map(String::from),
+        commit: commit.map(String::from),
+    };
+
+    if !revision.is_valid() {
+        eprintln!("Invalid ref or commit");
+        process::exit(1);
+    }
+
+    let mut parameters = Parameters::new();
+
+    for param in params {
+        let mut parts = param.splitn(2, '=');
+
+        match (parts.next(), parts.next()) {
+            (Some(name), Some(value)) if is_valid_parameter_name(name) => {
+                parameters.insert(name.into(), value.into());
+            }
+            _ => {
+                eprintln!("Invalid parameter {} (expected NAME=VALUE)", param);
+                process::exit(1);
+            }
+        }
+    }
+
+    let project = match config.projects.get(project_name) {
+        Some(project) => project,
+        None => {
+            eprintln!("Project {} does not exist", project_name);
+            process::exit(1);
+        }
+    };
+
+    // a dry run must not consume a job id, so we show the one the next job would receive
+    let id = if dry_run {
+        unwrap_err!(last_job_id(project_name)).map_or(1, |id| id + 1)
+    } else {
+        unwrap_err!(next_job_id(project_name))
+    };
+
+    let job = Job {
+        id,
+        project: project_name.into(),
+        priority: project.priority,
+        trigger: JobTrigger::Cli {
+            user: env::var("USER").unwrap_or_else(|_| "unknown".into()),
+        },
+        revision,
+        parameters,
+    };
+
+    if dry_run {
+        unwrap_err!(dry_run_job(&job, project));
+    } else {
+        unwrap_err!(run_job_foreground(&job, project));
+    }
+}
+
 pub fn telegram_setup() {
     let token = nanoid::generate(6);
     let config = unwrap_err!(get_config());
@@ -1,26 +1,70 @@
 use crate::config::get_config;
+use crate::retention::start_pruning;
+use crate::scheduler::start_scheduler;
 use crate::server::start_server;
-use crate::unwrap_err;
-use crate::worker::start_worker;
-use std::sync::mpsc::sync_channel;
+use crate::shutdown::{block_shutdown_signals, handle_shutdown};
+use crate::{status, unwrap_err};
+use crate::worker::{start_worker, JobQueue, WorkerState};
+use std::sync::Arc;
 use std::thread;
 
-// TODO: what value should I have here?
-// Note to future self: 8 was picked arbitrarily
-const CHANNEL_BOUND: usize = 8;
-
 pub fn start() {
     let config = unwrap_err!(get_config());
 
-    let (sender, receiver) = sync_channel(CHANNEL_BOUND);
+    block_shutdown_signals();
+
+    for (name, token) in &config.tokens {
+        if token.has_plaintext_secret() {
+            status!(
+                "Warning: token {} has a plaintext secret. Use `toby hash-secret` to hash it.",
+                name
+            );
+        }
+    }
+
+    let queue = Arc::new(JobQueue::new());
+    let state = Arc::new(WorkerState::new());
+
+    {
+        let config = config.clone();
+        let queue = queue.clone();
+        let state = state.clone();
+
+        thread::spawn(move || {
+            start_worker(&config, &queue, &state);
+        });
+    }
+
+    {
+        let config = config.clone();
+
+        thread::spawn(move || {
+            start_pruning(&config);
+        });
+    }
+
+    {
+        let config = config.clone();
+        let state = state.clone();
+
+        thread::spawn(move || {
+            handle_shutdown(&config, &state);
+        });
+    }
 
+    if config
+        .projects
+        .values()
+        .any(|project| !project.schedule.is_empty())
     {
         let config = config.clone();
+        let queue = queue.clone();
+        let state = state.clone();
 
         thread::spawn(move || {
-            start_worker(&config, &receiver);
+            start_scheduler(&config, &queue, &state);
         });
     }
 
-    start_server(config, sender);
+    start_server(config, queue, state);
 }
@@ -0,0 +1,114 @@
+use crate::config::{edit_tokens, get_tokens, hash_secret, EditError};
+use crate::unwrap_err;
+use glob::Pattern;
+use nanoid;
+use std::process;
+use std::slice::SliceConcatExt;
+use toml_edit::{value, Item, Table, Value};
+
+fn string_array(values: &[&str]) -> Value {
+    values.iter().cloned().collect()
+}
+
+pub fn add(name: &str, access: &[&str], permissions: Option<&[&str]>) {
+    // patterns are checked with the same parser that is used when loading the config
+    for pattern in access {
+        if let Err(err) = Pattern::new(pattern) {
+            eprintln!("Invalid access pattern {}: {}", pattern, err);
+            process::exit(1);
+        }
+    }
+
+    let secret = nanoid::simple();
+
+    unwrap_err!(edit_tokens(|document| {
+        if document.as_table().contains_key(name) {
+            return Err(EditError::TokenExists(name.into()));
+        }
+
+        let mut table = Table::new();
+
+        table["secret"] = value(hash_secret(&secret));
+        table["access"] = value(string_array(access));
+
+        if let Some(permissions) = permissions {
+            table["permissions"] = value(string_array(permissions));
+        }
+
+        document[name] = Item::Table(table);
+
+        Ok(())
+    }));
+
+    println!(
+        "Token {} added. Its secret is (it won't be shown again):",
+        name
+    );
+    println!("{}", secret);
+}
+
+pub fn list() {
+    let tokens = unwrap_err!(get_tokens());
+    let mut names: Vec<_> = tokens.keys().collect();
+
+    names.sort();
+
+    for name in names {
+        let token = &tokens[name];
+        let mut access: Vec<_> = token.access.iter().map(String::as_str).collect();
+        let mut permissions: Vec<_> = token.permissions.iter().map(ToString::to_string).collect();
+
+        access.sort();
+        permissions.sort();
+
+        println!("{}", name);
+        println!("  access: {}", access.join(", "));
+        println!("  permissions: {}", permissions.join(", "));
+
+        if let Some(ref expires_at) = token.expires_at {
+            println!("  expires at: {}", expires_at);
+        }
+
+        if !token.allow_from.is_empty() {
+            let allow_from: Vec<_> = token.allow_from.iter().map(ToString::to_string).collect();
+
+            println!("  allowed from: {}", allow_from.join(", "));
+        }
+
+        if token.has_plaintext_secret() {
+            println!("  warning: plaintext secret");
+        }
+    }
+}
+
+pub fn rotate(name: &str) {
+    let secret = nanoid::simple();
+
+    unwrap_err!(edit_tokens(|document| {
+        if !document.as_table().contains_table(name) {
+            return Err(EditError::TokenNotFound(name.into()));
+        }
+
+        document[name]["secret"] = value(hash_secret(&secret));
+
+        Ok(())
+    }));
+
+    println!(
+        "Secret of token {} rotated. The new secret is (it won't be shown again):",
+        name
+    );
+    println!("{}", secret);
+}
+
+pub fn revoke(name: &str) {
+    unwrap_err!(edit_tokens(|document| {
+        document
+            .as_table_mut()
+            .remove(name)
+            .map(|_| ())
+            .ok_or_else(|| EditError::TokenNotFound(name.into()))
+    }));
+
+    println!("Token {} revoked.", name);
+}
@@ -0,0 +1,231 @@
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+///
+/// An ip address range in CIDR notation (e.g. `10.0.0.0/8`).
+/// A bare ip address is treated as a range containing only that address.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Cidr {
+    address: IpAddr,
+    prefix: u8,
+}
+
+fn max_prefix(address: &IpAddr) -> u8 {
+    match *address {
+        IpAddr::V4(..) => 32,
+        IpAddr::V6(..) => 128,
+    }
+}
+
+///
+/// Converts an IPv4-mapped address (`::ffff:a.b.c.d`) to IPv4. Other addresses, including
+/// IPv4-compatible ones like `::1`, are not converted.
+///
+fn to_mapped_ipv4(address: &Ipv6Addr) -> Option<Ipv4Addr> {
+    match address.segments() {
+        [0, 0, 0, 0, 0, 0xffff, high, low] => Some(Ipv4Addr::new(
+            (high >> 8) as u8,
+            high as u8,
+            (low >> 8) as u8,
+            low as u8,
+        )),
+        _ => None,
+    }
+}
+
+fn mask(value: u128, prefix: u8, bits: u8) -> u128 {
+    if prefix == 0 {
+        0
+    } else {
+        value >> (bits - prefix)
+    }
+}
+
+impl Cidr {
+    pub(crate) fn contains(&self, address: IpAddr) -> bool {
+        // ipv4 clients may connect through an ipv6 socket
+        let address = match (self.address, address) {
+            (IpAddr::V4(..), IpAddr::V6(v6)) => match to_mapped_ipv4(&v6) {
+                Some(v4) => IpAddr::V4(v4),
+                None => return false,
+            },
+            (_, address) => address,
+        };
+
+        match (self.address, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let network = u128::from(u32::from(network));
+                let address = u128::from(u32::from(address));
+
+                mask(network, self.prefix, 32) == mask(address, self.prefix, 32)
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let network = u128::from(network);
+                let address = u128::from(address);
+
+                mask(network, self.prefix, 128) == mask(address, self.prefix, 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let mut parts = input.splitn(2, '/');
+
+        let address: IpAddr = parts
+            .next()
+            .unwrap_or_default()
+            .parse()
+            .map_err(|_| format!("invalid ip address in {}", input))?;
+
+        let prefix = match parts.next() {
+            Some(prefix) => prefix
+                .parse()
+                .map_err(|_| format!("invalid prefix length in {}", input))?,
+            None => max_prefix(&address),
+        };
+
+        if prefix > max_prefix(&address) {
+            return Err(format!("prefix length too large in {}", input));
+        }
+
+        Ok(Cidr { address, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix)
+    }
+}
+
+impl Serialize for Cidr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let string = String::deserialize(deserializer)?;
+
+        string.parse().map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(input: &str) -> Cidr {
+        input.parse().expect("invalid cidr")
+    }
+
+    fn ip(input: &str) -> IpAddr {
+        input.parse().expect("invalid ip address")
+    }
+
+    #[test]
+    fn matches_ipv4_ranges() {
+        let network = cidr("10.1.0.0/16");
+
+        assert!(network.contains(ip("10.1.0.0")));
+        assert!(network.contains(ip("10.1.255.255")));
+        assert!(!network.contains(ip("10.2.0.1")));
+        assert!(!network.contains(ip("11.1.0.1")));
+    }
+
+    #[test]
+    fn matches_single_ipv4_address() {
+        for input in &["192.168.1.10", "192.168.1.10/32"] {
+            let network = cidr(input);
+
+            assert!(network.contains(ip("192.168.1.10")));
+            assert!(!network.contains(ip("192.168.1.11")));
+        }
+    }
+
+    #[test]
+    fn matches_all_ipv4_addresses_with_prefix_0() {
+        let network = cidr("0.0.0.0/0");
+
+        assert!(network.contains(ip("1.2.3.4")));
+        assert!(network.contains(ip("255.255.255.255")));
+        assert!(!network.contains(ip("::1")));
+        assert!(!network.contains(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn matches_ipv6_ranges() {
+        let network = cidr("2001:db8::/32");
+
+        assert!(network.contains(ip("2001:db8::1")));
+        assert!(network.contains(ip("2001:db8:ffff::1")));
+        assert!(!network.contains(ip("2001:db9::1")));
+        assert!(!network.contains(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn matches_single_ipv6_address() {
+        for input in &["::1", "::1/128"] {
+            let network = cidr(input);
+
+            assert!(network.contains(ip("::1")));
+            assert!(!network.contains(ip("::2")));
+        }
+    }
+
+    #[test]
+    fn matches_all_ipv6_addresses_with_prefix_0() {
+        let network = cidr("::/0");
+
+        assert!(network.contains(ip("::1")));
+        assert!(network.contains(ip("ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff")));
+        assert!(!network.contains(ip("127.0.0.1")));
+    }
+
+    #[test]
+    fn matches_ipv4_mapped_addresses_against_ipv4_ranges() {
+        let network = cidr("127.0.0.0/8");
+
+        assert!(network.contains(ip("::ffff:127.0.0.1")));
+        assert!(!network.contains(ip("::ffff:128.0.0.1")));
+        // IPv4-compatible and other IPv6 addresses are not converted
+        assert!(!network.contains(ip("::127.0.0.1")));
+        assert!(!network.contains(ip("::1")));
+        assert!(!cidr("0.0.0.0/0").contains(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn rejects_invalid_ranges() {
+        for input in &[
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0.0/",
+            "10.0.0.0/-1",
+            "10.0.0/8",
+            "localhost",
+            "",
+        ] {
+            assert!(
+                input.parse::<Cidr>().is_err(),
+                "{} should be invalid",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn displays_the_prefix() {
+        assert_eq!("10.0.0.1/32", cidr("10.0.0.1").to_string());
+        assert_eq!("2001:db8::/32", cidr("2001:db8::/32").to_string());
+    }
+}
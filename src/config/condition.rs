@@ -0,0 +1,435 @@
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+///
+/// A condition that decides whether a script runs (the `when` field of a script), e.g.
+/// `trigger == "telegram" && !failed` or `params.migrate == "true" || env.FORCE_MIGRATION`.
+///
+#[derive(Debug, Clone)]
+pub(crate) struct Condition {
+    source: String,
+    expression: Expression,
+}
+
+///
+/// The values a condition can refer to.
+///
+#[derive(Debug)]
+pub(crate) struct Variables<'a> {
+    pub(crate) trigger: &'a str,
+    pub(crate) failed: bool,
+    pub(crate) environment: &'a BTreeMap<String, String>,
+    pub(crate) parameters: &'a BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expression {
+    Or(Box<Expression>, Box<Expression>),
+    And(Box<Expression>, Box<Expression>),
+    Not(Box<Expression>),
+    Equal(Value, Value),
+    NotEqual(Value, Value),
+    Value(Value),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Trigger,
+    Failed,
+    Environment(String),
+    Parameter(String),
+    Boolean(bool),
+    String(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Identifier(String),
+    String(String),
+    And,
+    Or,
+    Not,
+    Equal,
+    NotEqual,
+    OpenParen,
+    CloseParen,
+}
+
+#[derive(Debug, PartialEq)]
+enum Resolved<'a> {
+    Boolean(bool),
+    String(&'a str),
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.'
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = input.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        let next = chars.peek().cloned();
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::OpenParen,
+            ')' => Token::CloseParen,
+            '&' if next == Some('&') => {
+                chars.next();
+                Token::And
+            }
+            '|' if next == Some('|') => {
+                chars.next();
+                Token::Or
+            }
+            '=' if next == Some('=') => {
+                chars.next();
+                Token::Equal
+            }
+            '!' if next == Some('=') => {
+                chars.next();
+                Token::NotEqual
+            }
+            '!' => Token::Not,
+            '"' | '\'' => {
+                let mut string = String::new();
+
+                loop {
+                    match chars.next() {
+                        Some(end) if end == c => break,
+                        Some(c) => string.push(c),
+                        None => return Err("unterminated string".into()),
+                    }
+                }
+
+                Token::String(string)
+            }
+            c if is_identifier_char(c) => {
+                let mut identifier = c.to_string();
+
+                while chars.peek().map_or(false, |&c| is_identifier_char(c)) {
+                    identifier.extend(chars.next());
+                }
+
+                Token::Identifier(identifier)
+            }
+            c => return Err(format!("unexpected character {}", c)),
+        };
+
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+fn variable_name(name: &str) -> Result<String, String> {
+    if name.is_empty() || name.contains('.') {
+        Err(format!("invalid variable name {}", name))
+    } else {
+        Ok(name.to_string())
+    }
+}
+
+///
+/// A recursive descent parser for conditions. From lowest to highest precedence:
+/// `||`, `&&`, `!`, `==` and `!=`.
+///
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+
+        self.position += 1;
+
+        token
+    }
+
+    fn parse(mut self) -> Result<Expression, String> {
+        let expression = self.parse_or()?;
+
+        match self.next() {
+            None => Ok(expression),
+            Some(token) => Err(format!("unexpected {:?}", token)),
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Expression, String> {
+        let mut expression = self.parse_and()?;
+
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            expression = Expression::Or(Box::new(expression), Box::new(self.parse_and()?));
+        }
+
+        Ok(expression)
+    }
+
+    fn parse_and(&mut self) -> Result<Expression, String> {
+        let mut expression = self.parse_unary()?;
+
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            expression = Expression::And(Box::new(expression), Box::new(self.parse_unary()?));
+        }
+
+        Ok(expression)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expression, String> {
+        match self.peek().cloned() {
+            Some(Token::Not) => {
+                self.next();
+                Ok(Expression::Not(Box::new(self.parse_unary()?)))
+            }
+            Some(Token::OpenParen) => {
+                self.next();
+
+                let expression = self.parse_or()?;
+
+                match self.next() {
+                    Some(Token::CloseParen) => Ok(expression),
+                    _ => Err("missing )".into()),
+                }
+            }
+            _ => self.parse_comparison(),
+        }
+    }
+
+    fn parse_comparison(&mut self) -> Result<Expression, String> {
+        let left = self.parse_value()?;
+
+        match self.peek().cloned() {
+            Some(Token::Equal) => {
+                self.next();
+                Ok(Expression::Equal(left, self.parse_value()?))
+            }
+            Some(Token::NotEqual) => {
+                self.next();
+                Ok(Expression::NotEqual(left, self.parse_value()?))
+            }
+            _ => Ok(Expression::Value(left)),
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<Value, String> {
+        match self.next() {
+            Some(Token::String(string)) => Ok(Value::String(string)),
+            Some(Token::Identifier(identifier)) => match identifier.as_str() {
+                "trigger" => Ok(Value::Trigger),
+                "failed" => Ok(Value::Failed),
+                "true" => Ok(Value::Boolean(true)),
+                "false" => Ok(Value::Boolean(false)),
+                identifier if identifier.starts_with("env.") => {
+                    variable_name(&identifier[4..]).map(Value::Environment)
+                }
+                identifier if identifier.starts_with("params.") => {
+                    variable_name(&identifier[7..]).map(Value::Parameter)
+                }
+                identifier => Err(format!("unknown variable {}", identifier)),
+            },
+            Some(token) => Err(format!("unexpected {:?}", token)),
+            None => Err("unexpected end of condition".into()),
+        }
+    }
+}
+
+impl<'a> Resolved<'a> {
+    fn is_true(&self) -> bool {
+        match *self {
+            Resolved::Boolean(value) => value,
+            Resolved::String(value) => !value.is_empty(),
+        }
+    }
+
+    fn equals(&self, other: &Resolved) -> bool {
+        match (self, other) {
+            (&Resolved::Boolean(a), &Resolved::Boolean(b)) => a == b,
+            (&Resolved::String(a), &Resolved::String(b)) => a == b,
+            (&Resolved::Boolean(a), &Resolved::String(b))
+            | (&Resolved::String(b), &Resolved::Boolean(a)) => a.to_string() == b,
+        }
+    }
+}
+
+impl Value {
+    fn resolve<'a>(&'a self, variables: &'a Variables) -> Resolved<'a> {
+        let lookup = |map: &'a BTreeMap<String, String>, name: &str| {
+            Resolved::String(map.get(name).map_or("", String::as_str))
+        };
+
+        match *self {
+            Value::Trigger => Resolved::String(variables.trigger),
+            Value::Failed => Resolved::Boolean(variables.failed),
+            Value::Environment(ref name) => lookup(variables.environment, name),
+            Value::Parameter(ref name) => lookup(variables.parameters, name),
+            Value::Boolean(value) => Resolved::Boolean(value),
+            Value::String(ref value) => Resolved::String(value),
+        }
+    }
+}
+
+impl Expression {
+    fn evaluate(&self, variables: &Variables) -> bool {
+        match *self {
+            Expression::Or(ref a, ref b) => a.evaluate(variables) || b.evaluate(variables),
+            Expression::And(ref a, ref b) => a.evaluate(variables) && b.evaluate(variables),
+            Expression::Not(ref a) => !a.evaluate(variables),
+            Expression::Equal(ref a, ref b) => a.resolve(variables).equals(&b.resolve(variables)),
+            Expression::NotEqual(ref a, ref b) => {
+                !a.resolve(variables).equals(&b.resolve(variables))
+            }
+            Expression::Value(ref value) => value.resolve(variables).is_true(),
+        }
+    }
+}
+
+impl Condition {
+    pub(crate) fn evaluate(&self, variables: &Variables) -> bool {
+        self.expression.evaluate(variables)
+    }
+}
+
+impl FromStr for Condition {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let expression = tokenize(input)
+            .and_then(|tokens| {
+                let parser = Parser {
+                    tokens,
+                    position: 0,
+                };
+
+                parser.parse()
+            })
+            .map_err(|err| format!("invalid condition {}: {}", input, err))?;
+
+        Ok(Condition {
+            source: input.to_string(),
+            expression,
+        })
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl Serialize for Condition {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Condition {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let string = String::deserialize(deserializer)?;
+
+        string.parse().map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn evaluate(condition: &str, trigger: &str, failed: bool) -> bool {
+        let environment = vec![("FORCE".to_string(), "1".to_string())]
+            .into_iter()
+            .collect();
+        let parameters = vec![("env".to_string(), "staging".to_string())]
+            .into_iter()
+            .collect();
+        let variables = Variables {
+            trigger,
+            failed,
+            environment: &environment,
+            parameters: &parameters,
+        };
+
+        condition
+            .parse::<Condition>()
+            .expect("invalid condition")
+            .evaluate(&variables)
+    }
+
+    fn parse_error(condition: &str) -> String {
+        condition
+            .parse::<Condition>()
+            .expect_err("condition should be invalid")
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        assert!(evaluate("true || false && false", "cli", false));
+        assert!(!evaluate("(true || false) && false", "cli", false));
+    }
+
+    #[test]
+    fn not_applies_to_comparisons() {
+        assert!(evaluate(r#"!trigger == "webhook""#, "cli", false));
+        assert!(!evaluate(r#"!trigger == "cli""#, "cli", false));
+        assert!(evaluate("!failed && !!true", "cli", false));
+    }
+
+    #[test]
+    fn compares_variables() {
+        assert!(evaluate(r#"trigger == "cli" && failed"#, "cli", true));
+        assert!(evaluate(r#"params.env != "production""#, "cli", false));
+        assert!(evaluate(r#"failed == "true""#, "cli", true));
+        assert!(evaluate("env.FORCE", "cli", false));
+    }
+
+    #[test]
+    fn unknown_parameters_and_variables_are_empty() {
+        assert!(!evaluate("params.missing", "cli", false));
+        assert!(!evaluate("env.MISSING", "cli", false));
+        assert!(evaluate(r#"params.missing == """#, "cli", false));
+    }
+
+    #[test]
+    fn accepts_single_and_double_quotes() {
+        assert!(evaluate("params.env == 'staging'", "cli", false));
+        assert!(evaluate(r#"params.env == "staging""#, "cli", false));
+        assert!(evaluate(r#"trigger != 'it"s'"#, "cli", false));
+        assert!(evaluate(r#"'a && b' == "a && b""#, "cli", false));
+    }
+
+    #[test]
+    fn rejects_unknown_identifiers() {
+        assert!(parse_error("triggered").contains("unknown variable triggered"));
+        assert!(parse_error("env.").contains("invalid variable name"));
+        assert!(parse_error("params.a.b").contains("invalid variable name"));
+    }
+
+    #[test]
+    fn rejects_invalid_syntax() {
+        assert!(parse_error(r#"trigger == "cli"#).contains("unterminated string"));
+        assert!(parse_error("(failed").contains("missing )"));
+        assert!(parse_error("failed &&").contains("unexpected end of condition"));
+        assert!(parse_error("failed failed").contains("unexpected"));
+        assert!(parse_error("failed & true").contains("unexpected character &"));
+        assert!(parse_error("").contains("unexpected end of condition"));
+    }
+
+    #[test]
+    fn displays_the_source() {
+        let condition: Condition = "trigger == 'cli'".parse().unwrap();
+
+        assert_eq!("trigger == 'cli'", condition.to_string());
+    }
+}
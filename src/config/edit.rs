@@ -0,0 +1,82 @@
+use super::find::find_tokens_file;
+use super::Tokens;
+use crate::fs::FileLock;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use toml;
+use toml_edit::{self, Document};
+
+#[derive(Debug)]
+pub(crate) enum EditError {
+    Io(io::Error),
+    Parse(toml_edit::TomlError),
+    Invalid(toml::de::Error),
+    TokenExists(String),
+    TokenNotFound(String),
+}
+
+impl From<io::Error> for EditError {
+    fn from(err: io::Error) -> Self {
+        EditError::Io(err)
+    }
+}
+
+impl fmt::Display for EditError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EditError::Io(ref err) => write!(f, "Unable to edit config file: {}", err),
+            EditError::Parse(ref err) => write!(f, "Unable to parse config file:\n{}", err),
+            EditError::Invalid(ref err) => {
+                write!(f, "Edit would result in invalid config:\n{}", err)
+            }
+            EditError::TokenExists(ref name) => write!(f, "Token {} already exists", name),
+            EditError::TokenNotFound(ref name) => write!(f, "Token {} does not exist", name),
+        }
+    }
+}
+
+///
+/// Edits a config file in place while holding an exclusive lock on it.
+/// Comments and formatting of the untouched parts are preserved.
+///
+fn edit_file<F, V>(path: &Path, edit: F, validate: V) -> Result<(), EditError>
+where
+    F: FnOnce(&mut Document) -> Result<(), EditError>,
+    V: FnOnce(&str) -> Result<(), EditError>,
+{
+    let file = OpenOptions::new().read(true).write(true).open(path)?;
+    let mut file = FileLock::exclusive(file)?;
+    let mut contents = String::new();
+
+    file.read_to_string(&mut contents)?;
+
+    let mut document: Document = contents.parse().map_err(EditError::Parse)?;
+
+    edit(&mut document)?;
+
+    let contents = document.to_string();
+
+    validate(&contents)?;
+
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(contents.as_bytes())?;
+    file.file().set_len(contents.len() as u64)?;
+
+    Ok(())
+}
+
+///
+/// Edits `tokens.toml` in place. The edited file is checked to still be a valid tokens config.
+///
+pub(crate) fn edit_tokens<F>(edit: F) -> Result<(), EditError>
+where
+    F: FnOnce(&mut Document) -> Result<(), EditError>,
+{
+    edit_file(&find_tokens_file(), edit, |contents| {
+        toml::from_str::<Tokens>(contents)
+            .map(|_| ())
+            .map_err(EditError::Invalid)
+    })
+}
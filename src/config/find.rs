@@ -6,6 +6,7 @@ const LOCAL_CONFIG_DIR: &str = env!("TOBY_CONFIG_PATH");
 
 const CONFIG_EXTENSION: &str = "toml";
 const PROJECT_CONFIG_PATH: &str = "conf.d";
+const SCRIPTS_PATH: &str = "scripts.d";
 const CONFIG_PATH: &str = "toby.toml";
 const TOKENS_PATH: &str = "tokens.toml";
 
@@ -43,6 +44,34 @@ pub(crate) fn find_project_configs() -> io::Result<Vec<PathBuf>> {
     Ok(files)
 }
 
+pub(crate) fn find_project_config(project_name: &str) -> PathBuf {
+    let mut path = prefix_path(PROJECT_CONFIG_PATH);
+
+    path.push(project_name);
+    path.set_extension(CONFIG_EXTENSION);
+
+    path
+}
+
+///
+/// Looks up a script in `scripts.d` by its file name.
+///
+pub(crate) fn find_script(name: &str) -> Option<PathBuf> {
+    if name.contains('/') {
+        return None;
+    }
+
+    let mut path = prefix_path(SCRIPTS_PATH);
+
+    path.push(name);
+
+    if path.is_file() {
+        Some(path)
+    } else {
+        None
+    }
+}
+
 pub(crate) fn find_config_file() -> PathBuf {
     prefix_path(CONFIG_PATH)
 }
@@ -1,9 +1,21 @@
 mod model;
 mod find;
-
+mod cidr;
+mod condition;
+mod edit;
+mod schedule;
+mod secret;
+mod validate;
+
+pub(crate) use self::condition::{Condition, Variables};
+pub(crate) use self::edit::{edit_tokens, EditError};
+pub(crate) use self::find::{find_project_config, find_script};
 pub(crate) use self::model::*;
+pub(crate) use self::schedule::Schedule;
+pub(crate) use self::secret::hash_secret;
 
 use self::find::{find_config_file, find_project_configs, find_tokens_file};
+use self::validate::validate;
 use std::collections::HashMap;
 use std::fmt;
 use std::fs::File;
@@ -17,6 +29,7 @@ pub(crate) enum ConfigError {
     ListError,
     ReadError(PathBuf),
     ParseError(PathBuf, toml::de::Error),
+    Invalid(String),
 }
 
 impl fmt::Display for ConfigError {
@@ -35,6 +48,7 @@ impl fmt::Display for ConfigError {
             ConfigError::NotFound(ref path) => {
                 write!(f, "Config file {} does not exist", path.to_string_lossy())
             }
+            ConfigError::Invalid(ref message) => write!(f, "Invalid configuration: {}", message),
         }
     }
 }
@@ -86,7 +100,11 @@ pub(crate) fn get_config() -> Result<Config, ConfigError> {
     let tokens = get_tokens()?;
     let projects = get_projects()?;
 
-    Ok(Config::new(main, tokens, projects))
+    let config = Config::new(main, tokens, projects);
+
+    validate(&config)?;
+
+    Ok(config)
 }
 
 pub(crate) fn get_main_config() -> Result<MainConfig, ConfigError> {
@@ -1,4 +1,27 @@
+use super::cidr::Cidr;
+use super::condition::Condition;
+use super::schedule::Schedule;
+use super::secret::{is_hashed, verify_secret};
+use crate::time::parse_rfc3339;
+use glob::Pattern;
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
 use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::slice::SliceConcatExt;
+use std::str::FromStr;
+use std::time::Duration;
+use toml::value::Datetime;
+
+const DEFAULT_SHELL: &[&str] = &["/bin/sh", "-e"];
+
+/// The delay before the first retry of a script (in seconds)
+const DEFAULT_RETRY_DELAY: u64 = 1;
+
+/// The delay between retries stops doubling after this many retries
+const MAX_BACKOFF_EXPONENT: u32 = 10;
 
 pub(crate) type Projects = HashMap<String, Project>;
 pub(crate) type Tokens = HashMap<String, Token>;
@@ -15,15 +38,139 @@ pub(crate) struct Config {
 pub(crate) struct Project {
     pub(crate) scripts: Vec<Script>,
     #[serde(default)]
+    pub(crate) on_success: Vec<Script>,
+    #[serde(default)]
+    pub(crate) on_failure: Vec<Script>,
+    #[serde(default)]
+    pub(crate) finally: Vec<Script>,
+    #[serde(default)]
     pub(crate) environment: HashMap<String, String>,
+    #[serde(default)]
+    pub(crate) inherit_environment: InheritEnvironment,
+    pub(crate) log_format: Option<LogFormat>,
+    #[serde(default)]
+    pub(crate) retention: RetentionConfig,
+    #[serde(default)]
+    pub(crate) workspace: Workspace,
+    #[serde(default)]
+    pub(crate) clean: Clean,
+    pub(crate) source: Option<SourceConfig>,
+    pub(crate) user: Option<String>,
+    pub(crate) group: Option<String>,
+    #[serde(default)]
+    pub(crate) groups: Vec<String>,
+    #[serde(default)]
+    pub(crate) limits: LimitsConfig,
+    #[serde(default, deserialize_with = "deserialize_schedules")]
+    pub(crate) schedule: Vec<Schedule>,
+    #[serde(default)]
+    pub(crate) on_success_trigger: Vec<String>,
+    #[serde(default)]
+    pub(crate) on_failure_trigger: Vec<String>,
+    #[serde(default)]
+    pub(crate) artifacts: Vec<String>,
+    pub(crate) approval: Option<ApprovalConfig>,
+    #[serde(default)]
+    pub(crate) lock_groups: Vec<String>,
+    #[serde(default)]
+    pub(crate) priority: i32,
+}
+
+///
+/// Resource limits for the commands of a project. Unset limits are not enforced.
+///
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct LimitsConfig {
+    pub(crate) cpu_time_secs: Option<u64>,
+    pub(crate) address_space_mb: Option<u64>,
+    pub(crate) open_files: Option<u64>,
+    pub(crate) processes: Option<u64>,
+    pub(crate) memory_mb: Option<u64>,
+    pub(crate) cpu_percent: Option<u64>,
+}
+
+///
+/// Requires jobs of a project to be approved through the HTTP API before their scripts run.
+///
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ApprovalConfig {
+    /// Jobs that haven't been approved within this time are rejected
+    pub(crate) timeout_mins: Option<u64>,
+}
+
+///
+/// A git repository that is checked out into the workspace before the scripts run.
+///
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SourceConfig {
+    pub(crate) repository: String,
+    #[serde(rename = "ref")]
+    pub(crate) git_ref: Option<String>,
+    pub(crate) depth: Option<u32>,
+    #[serde(default)]
+    pub(crate) submodules: bool,
+}
+
+///
+/// The directory the scripts of a project are run in.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Workspace {
+    /// A fresh temporary directory that is deleted after the job
+    Temp,
+    /// A directory under the runtime path that is kept between jobs
+    Persistent,
+    /// An explicit directory that is kept between jobs
+    Path(PathBuf),
+}
+
+///
+/// Which environment variables of tobyd are passed on to the scripts.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum InheritEnvironment {
+    All,
+    None,
+    Only(Vec<String>),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum InheritEnvironmentRepr {
+    Keyword(String),
+    Only(Vec<String>),
+}
+
+///
+/// When a persistent workspace is emptied.
+///
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Clean {
+    Never,
+    Always,
+    OnFailure,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(deny_unknown_fields)]
 pub(crate) struct Script {
+    pub(crate) name: Option<String>,
+    #[serde(default)]
     pub(crate) command: Vec<String>,
+    pub(crate) run: Option<String>,
+    pub(crate) shell: Option<Vec<String>>,
     #[serde(default)]
     pub(crate) allow_failure: bool,
+    pub(crate) when: Option<Condition>,
+    #[serde(default)]
+    pub(crate) retries: u32,
+    pub(crate) retry_delay: Option<u64>,
+    #[serde(default)]
+    pub(crate) retry_on: Vec<i32>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -31,6 +178,12 @@ pub(crate) struct Script {
 pub(crate) struct MainConfig {
     #[serde(default)]
     pub(crate) listen: ListenConfig,
+    #[serde(default)]
+    pub(crate) log_format: LogFormat,
+    #[serde(default)]
+    pub(crate) retention: RetentionConfig,
+    #[serde(default)]
+    pub(crate) shutdown: ShutdownConfig,
     pub(crate) telegram: Option<TelegramConfig>,
     pub(crate) tls: Option<TlsConfig>,
 }
@@ -44,6 +197,29 @@ pub(crate) struct ListenConfig {
     pub(crate) address: String,
 }
 
+///
+/// Limits for how many finished jobs (archives and logs) are kept around.
+/// Unset limits are not enforced.
+///
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RetentionConfig {
+    pub(crate) max_age_days: Option<u64>,
+    pub(crate) max_jobs: Option<usize>,
+    pub(crate) max_size_mb: Option<u64>,
+    pub(crate) compress_after_days: Option<u64>,
+}
+
+///
+/// How tobyd shuts down when it receives SIGTERM or SIGINT.
+///
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ShutdownConfig {
+    #[serde(default = "default_grace_period")]
+    pub(crate) grace_period_secs: u64,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(deny_unknown_fields)]
 pub(crate) struct TelegramConfig {
@@ -61,6 +237,13 @@ pub(crate) enum SendLog {
     Failure,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum LogFormat {
+    Text,
+    Json,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(deny_unknown_fields)]
 pub(crate) struct TlsConfig {
@@ -73,16 +256,43 @@ pub(crate) struct TlsConfig {
 pub(crate) struct Token {
     pub(crate) secret: String,
     pub(crate) access: HashSet<String>,
+    pub(crate) expires_at: Option<Datetime>,
+    #[serde(default)]
+    pub(crate) allow_from: Vec<Cidr>,
+    #[serde(default = "default_permissions")]
+    pub(crate) permissions: HashSet<Permission>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Permission {
+    Trigger,
+    ReadStatus,
+    ReadLogs,
+    ReadArtifacts,
+    Cancel,
+    Approve,
+    Prioritize,
 }
 
 fn default_port() -> u16 {
     8629
 }
 
+fn default_grace_period() -> u64 {
+    60
+}
+
 fn default_address() -> String {
     "0.0.0.0".into()
 }
 
+fn default_permissions() -> HashSet<Permission> {
+    let mut permissions = HashSet::new();
+    permissions.insert(Permission::Trigger);
+    permissions
+}
+
 impl Config {
     pub(crate) fn new(main: MainConfig, tokens: Tokens, projects: Projects) -> Self {
         Config {
@@ -105,7 +315,167 @@ impl TlsConfig {
 
 impl Token {
     pub(crate) fn can_access(&self, project: &str) -> bool {
-        self.access.contains(project)
+        self.access.iter().any(|pattern| {
+            Pattern::new(pattern)
+                .map(|pattern| pattern.matches(project))
+                .unwrap_or(false)
+        })
+    }
+
+    pub(crate) fn can(&self, permission: Permission, project: &str) -> bool {
+        self.permissions.contains(&permission) && self.can_access(project)
+    }
+
+    pub(crate) fn expires_at(&self) -> Option<u64> {
+        self.expires_at
+            .as_ref()
+            .and_then(|expires_at| parse_rfc3339(&expires_at.to_string()))
+    }
+
+    pub(crate) fn is_expired(&self, now: u64) -> bool {
+        self.expires_at()
+            .map_or(false, |expires_at| expires_at <= now)
+    }
+
+    pub(crate) fn allows_address(&self, address: IpAddr) -> bool {
+        self.allow_from.is_empty() || self.allow_from.iter().any(|cidr| cidr.contains(address))
+    }
+
+    pub(crate) fn verify_secret(&self, secret: &str) -> bool {
+        verify_secret(&self.secret, secret)
+    }
+
+    pub(crate) fn has_plaintext_secret(&self) -> bool {
+        !is_hashed(&self.secret)
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Permission::Trigger => "trigger",
+            Permission::ReadStatus => "read_status",
+            Permission::ReadLogs => "read_logs",
+            Permission::ReadArtifacts => "read_artifacts",
+            Permission::Cancel => "cancel",
+            Permission::Approve => "approve",
+            Permission::Prioritize => "prioritize",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+impl Project {
+    pub(crate) fn log_format(&self, main: &MainConfig) -> LogFormat {
+        self.log_format.unwrap_or(main.log_format)
+    }
+
+    ///
+    /// The retention policy of the project. Limits that are not set for the project
+    /// fall back to the ones from the main config.
+    ///
+    pub(crate) fn retention(&self, main: &MainConfig) -> RetentionConfig {
+        let project = &self.retention;
+        let main = &main.retention;
+
+        RetentionConfig {
+            max_age_days: project.max_age_days.or(main.max_age_days),
+            max_jobs: project.max_jobs.or(main.max_jobs),
+            max_size_mb: project.max_size_mb.or(main.max_size_mb),
+            compress_after_days: project.compress_after_days.or(main.compress_after_days),
+        }
+    }
+
+    ///
+    /// The projects that are triggered after a job of this project has finished.
+    ///
+    pub(crate) fn downstream(&self, successful: bool) -> &[String] {
+        if successful {
+            &self.on_success_trigger
+        } else {
+            &self.on_failure_trigger
+        }
+    }
+}
+
+impl ApprovalConfig {
+    pub(crate) fn timeout(&self) -> Option<Duration> {
+        self.timeout_mins.map(|mins| Duration::from_secs(mins * 60))
+    }
+}
+
+impl LimitsConfig {
+    ///
+    /// Whether any of the limits requires a cgroup.
+    ///
+    pub(crate) fn needs_cgroup(&self) -> bool {
+        self.memory_mb.is_some() || self.cpu_percent.is_some()
+    }
+}
+
+impl RetentionConfig {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.max_age_days.is_none()
+            && self.max_jobs.is_none()
+            && self.max_size_mb.is_none()
+            && self.compress_after_days.is_none()
+    }
+}
+
+impl Script {
+    ///
+    /// The name of the script, falling back to its command.
+    ///
+    pub(crate) fn display_name(&self) -> String {
+        match self.name {
+            Some(ref name) => name.clone(),
+            None => self.summary(),
+        }
+    }
+
+    ///
+    /// The command of the script, or the first line of its `run` source.
+    ///
+    pub(crate) fn summary(&self) -> String {
+        match self.run {
+            Some(ref run) => run
+                .lines()
+                .map(str::trim)
+                .find(|line| !line.is_empty())
+                .unwrap_or_default()
+                .to_string(),
+            None => self.command.join(" "),
+        }
+    }
+
+    ///
+    /// Whether a failed attempt with the given exit code should be retried
+    /// (ignoring the number of retries).
+    ///
+    pub(crate) fn should_retry(&self, exit_code: Option<i32>) -> bool {
+        self.retry_on.is_empty()
+            || exit_code.map_or(false, |exit_code| self.retry_on.contains(&exit_code))
+    }
+
+    ///
+    /// The delay before the given retry (starting at 1). The delay doubles with each retry.
+    ///
+    pub(crate) fn retry_delay(&self, retry: u32) -> Duration {
+        let delay = self.retry_delay.unwrap_or(DEFAULT_RETRY_DELAY);
+        let factor = 1 << (retry.max(1) - 1).min(MAX_BACKOFF_EXPONENT);
+
+        Duration::from_secs(delay.saturating_mul(factor))
+    }
+
+    ///
+    /// The shell that runs the script's `run` source.
+    ///
+    pub(crate) fn shell(&self) -> Vec<String> {
+        match self.shell {
+            Some(ref shell) => shell.clone(),
+            None => DEFAULT_SHELL.iter().map(|arg| arg.to_string()).collect(),
+        }
     }
 }
 
@@ -118,12 +488,144 @@ impl Default for ListenConfig {
     }
 }
 
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig {
+            grace_period_secs: default_grace_period(),
+        }
+    }
+}
+
+impl ShutdownConfig {
+    ///
+    /// How long to wait for the running job before interrupting it.
+    ///
+    pub(crate) fn grace_period(&self) -> Duration {
+        Duration::from_secs(self.grace_period_secs)
+    }
+}
+
+impl Default for Workspace {
+    fn default() -> Self {
+        Workspace::Temp
+    }
+}
+
+impl Default for InheritEnvironment {
+    fn default() -> Self {
+        InheritEnvironment::All
+    }
+}
+
+impl Serialize for InheritEnvironment {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let repr = match *self {
+            InheritEnvironment::All => InheritEnvironmentRepr::Keyword("all".into()),
+            InheritEnvironment::None => InheritEnvironmentRepr::Keyword("none".into()),
+            InheritEnvironment::Only(ref names) => InheritEnvironmentRepr::Only(names.clone()),
+        };
+
+        repr.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for InheritEnvironment {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match InheritEnvironmentRepr::deserialize(deserializer)? {
+            InheritEnvironmentRepr::Keyword(ref keyword) if keyword == "all" => {
+                Ok(InheritEnvironment::All)
+            }
+            InheritEnvironmentRepr::Keyword(ref keyword) if keyword == "none" => {
+                Ok(InheritEnvironment::None)
+            }
+            InheritEnvironmentRepr::Keyword(keyword) => Err(de::Error::custom(format!(
+                "expected all, none or a list of variables, found {}",
+                keyword
+            ))),
+            InheritEnvironmentRepr::Only(names) => Ok(InheritEnvironment::Only(names)),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SchedulesRepr {
+    One(String),
+    Many(Vec<String>),
+}
+
+///
+/// Accepts either a single schedule or a list of schedules.
+///
+fn deserialize_schedules<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<Schedule>, D::Error> {
+    let schedules = match SchedulesRepr::deserialize(deserializer)? {
+        SchedulesRepr::One(schedule) => vec![schedule],
+        SchedulesRepr::Many(schedules) => schedules,
+    };
+
+    schedules
+        .iter()
+        .map(|schedule| schedule.parse().map_err(de::Error::custom))
+        .collect()
+}
+
+impl Default for Clean {
+    fn default() -> Self {
+        Clean::Never
+    }
+}
+
+impl FromStr for Workspace {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "temp" => Ok(Workspace::Temp),
+            "persistent" => Ok(Workspace::Persistent),
+            "" => Err("workspace must not be empty".into()),
+            path => Ok(Workspace::Path(path.into())),
+        }
+    }
+}
+
+impl fmt::Display for Workspace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Workspace::Temp => write!(f, "temp"),
+            Workspace::Persistent => write!(f, "persistent"),
+            Workspace::Path(ref path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+impl Serialize for Workspace {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Workspace {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let string = String::deserialize(deserializer)?;
+
+        string.parse().map_err(de::Error::custom)
+    }
+}
+
 impl Default for SendLog {
     fn default() -> Self {
         SendLog::Never
     }
 }
 
+impl Default for LogFormat {
+    fn default() -> Self {
+        LogFormat::Text
+    }
+}
+
 impl SendLog {
     pub(crate) fn should_send(self, successful: bool) -> bool {
         match self {
@@ -134,3 +636,53 @@ impl SendLog {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use toml;
+
+    fn token(fields: &str) -> Token {
+        let config = format!("secret = \"secret\"\naccess = [\"*\"]\n{}", fields);
+
+        toml::from_str(&config).expect("invalid token")
+    }
+
+    #[test]
+    fn token_expires_at_the_configured_time() {
+        let token = token("expires_at = 2018-07-14T15:37:00+02:00");
+
+        assert_eq!(Some(1_531_575_420), token.expires_at());
+        assert!(!token.is_expired(1_531_575_419));
+        assert!(token.is_expired(1_531_575_420));
+    }
+
+    #[test]
+    fn token_with_expiry_date_expires_at_midnight() {
+        let token = token("expires_at = 2018-07-14");
+
+        assert!(!token.is_expired(1_531_526_399));
+        assert!(token.is_expired(1_531_526_400));
+    }
+
+    #[test]
+    fn token_without_expiry_never_expires() {
+        assert!(!token("").is_expired(u64::max_value()));
+    }
+
+    #[test]
+    fn token_is_restricted_to_allowed_addresses() {
+        let token = token("allow_from = [\"10.0.0.0/8\", \"::1\"]");
+
+        assert!(token.allows_address("10.1.2.3".parse().unwrap()));
+        assert!(token.allows_address("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(token.allows_address("::1".parse().unwrap()));
+        assert!(!token.allows_address("192.168.1.1".parse().unwrap()));
+        assert!(!token.allows_address("::2".parse().unwrap()));
+    }
+
+    #[test]
+    fn token_without_allow_from_allows_all_addresses() {
+        assert!(token("").allows_address("192.168.1.1".parse().unwrap()));
+    }
+}
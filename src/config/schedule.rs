@@ -0,0 +1,283 @@
+use crate::time::CalendarTime;
+use serde::ser::{Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+/// Shorthands for common schedules
+const SHORTHANDS: &[(&str, &str)] = &[
+    ("@hourly", "0 * * * *"),
+    ("@daily", "0 0 * * *"),
+    ("@weekly", "0 0 * * 0"),
+    ("@monthly", "0 0 1 * *"),
+    ("@yearly", "0 0 1 1 *"),
+];
+
+///
+/// A cron-style schedule (`minute hour day month weekday`), evaluated in UTC.
+///
+/// Fields can be `*`, a number, a range (`1-5`), a step (`*/15`, `0-30/10`) or a list of those
+/// (`0,30`). Sunday is both 0 and 7.
+///
+#[derive(Debug, Clone)]
+pub(crate) struct Schedule {
+    source: String,
+    minutes: Field,
+    hours: Field,
+    days: Field,
+    months: Field,
+    weekdays: Field,
+}
+
+///
+/// The values a field matches, as a bit set.
+///
+#[derive(Debug, Clone, Copy)]
+struct Field {
+    values: u64,
+    any: bool,
+}
+
+fn parse_number(input: &str, min: u32, max: u32) -> Result<u32, String> {
+    match input.parse() {
+        Ok(number) if number >= min && number <= max => Ok(number),
+        _ => Err(format!(
+            "expected a number from {} to {}, found {}",
+            min, max, input
+        )),
+    }
+}
+
+impl Field {
+    fn parse(input: &str, min: u32, max: u32) -> Result<Self, String> {
+        let mut values = 0;
+
+        for part in input.split(',') {
+            let mut range_step = part.splitn(2, '/');
+            let range = range_step.next().unwrap_or_default();
+            let step = match range_step.next() {
+                Some(step) => parse_number(step, 1, max)?,
+                None => 1,
+            };
+
+            let (start, end) = if range == "*" {
+                (min, max)
+            } else {
+                let mut bounds = range.splitn(2, '-');
+                let start = parse_number(bounds.next().unwrap_or_default(), min, max)?;
+
+                match bounds.next() {
+                    Some(end) => (start, parse_number(end, start, max)?),
+                    // `5/10` is short for `5-<max>/10`
+                    None if step > 1 => (start, max),
+                    None => (start, start),
+                }
+            };
+
+            let mut value = start;
+
+            while value <= end {
+                values |= 1 << value;
+                value += step;
+            }
+        }
+
+        Ok(Field {
+            values,
+            // like cron, fields starting with `*` are unrestricted (even with a step)
+            any: input.starts_with('*'),
+        })
+    }
+
+    fn contains(&self, value: u32) -> bool {
+        self.values & (1 << value) != 0
+    }
+}
+
+impl Schedule {
+    ///
+    /// Whether the schedule is due at the given minute.
+    ///
+    pub(crate) fn matches(&self, time: &CalendarTime) -> bool {
+        // like cron, a restricted day and weekday match if either of them does
+        let day = match (self.days.any, self.weekdays.any) {
+            (false, false) => self.days.contains(time.day) || self.weekdays.contains(time.weekday),
+            _ => self.days.contains(time.day) && self.weekdays.contains(time.weekday),
+        };
+
+        day && self.minutes.contains(time.minute)
+            && self.hours.contains(time.hour)
+            && self.months.contains(time.month)
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let expanded = SHORTHANDS
+            .iter()
+            .find(|&&(shorthand, _)| shorthand == input)
+            .map_or(input, |&(_, expanded)| expanded);
+
+        let fields: Vec<_> = expanded.split_whitespace().collect();
+
+        if fields.len() != 5 {
+            return Err(format!(
+                "invalid schedule {}: expected 5 fields (minute hour day month weekday)",
+                input
+            ));
+        }
+
+        let parse = |index: usize, min: u32, max: u32| {
+            Field::parse(fields[index], min, max)
+                .map_err(|err| format!("invalid schedule {}: {}", input, err))
+        };
+
+        let mut weekdays = parse(4, 0, 7)?;
+
+        // 7 is an alias for sunday
+        if weekdays.contains(7) {
+            weekdays.values |= 1;
+        }
+
+        Ok(Schedule {
+            source: input.to_string(),
+            minutes: parse(0, 0, 59)?,
+            hours: parse(1, 0, 23)?,
+            days: parse(2, 1, 31)?,
+            months: parse(3, 1, 12)?,
+            weekdays,
+        })
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl Serialize for Schedule {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(minute: u32, hour: u32, day: u32, month: u32, weekday: u32) -> CalendarTime {
+        CalendarTime {
+            minute,
+            hour,
+            day,
+            month,
+            weekday,
+        }
+    }
+
+    fn schedule(input: &str) -> Schedule {
+        input.parse().expect("invalid schedule")
+    }
+
+    #[test]
+    fn matches_ranges() {
+        let schedule = schedule("0 9-17 * * 1-5");
+
+        assert!(schedule.matches(&at(0, 9, 6, 1, 1)));
+        assert!(schedule.matches(&at(0, 17, 10, 1, 5)));
+        assert!(!schedule.matches(&at(0, 18, 10, 1, 5)));
+        assert!(!schedule.matches(&at(30, 9, 6, 1, 1)));
+        assert!(!schedule.matches(&at(0, 9, 11, 1, 6)));
+    }
+
+    #[test]
+    fn matches_steps() {
+        let every_quarter = schedule("*/15 * * * *");
+        let from_five = schedule("5/20 * * * *");
+        let first_half = schedule("0-30/10 * * * *");
+
+        for &minute in &[0, 15, 30, 45] {
+            assert!(every_quarter.matches(&at(minute, 0, 1, 1, 3)));
+        }
+
+        assert!(!every_quarter.matches(&at(10, 0, 1, 1, 3)));
+        assert!(from_five.matches(&at(45, 0, 1, 1, 3)));
+        assert!(!from_five.matches(&at(0, 0, 1, 1, 3)));
+        assert!(first_half.matches(&at(30, 0, 1, 1, 3)));
+        assert!(!first_half.matches(&at(40, 0, 1, 1, 3)));
+    }
+
+    #[test]
+    fn matches_lists() {
+        let schedule = schedule("0,30 0 1,15 * *");
+
+        assert!(schedule.matches(&at(30, 0, 15, 6, 2)));
+        assert!(!schedule.matches(&at(15, 0, 15, 6, 2)));
+        assert!(!schedule.matches(&at(0, 0, 14, 6, 2)));
+    }
+
+    #[test]
+    fn matches_day_or_weekday_if_both_are_restricted() {
+        let schedule = schedule("0 0 13 * 5");
+
+        assert!(schedule.matches(&at(0, 0, 13, 3, 1)));
+        assert!(schedule.matches(&at(0, 0, 1, 3, 5)));
+        assert!(!schedule.matches(&at(0, 0, 2, 3, 6)));
+    }
+
+    #[test]
+    fn matches_day_and_weekday_if_one_is_unrestricted() {
+        let weekday_only = schedule("0 0 * * 5");
+        let day_only = schedule("0 0 13 * *");
+        let odd_mondays = schedule("0 0 */2 * 1");
+
+        assert!(weekday_only.matches(&at(0, 0, 1, 3, 5)));
+        assert!(!weekday_only.matches(&at(0, 0, 13, 3, 1)));
+        assert!(day_only.matches(&at(0, 0, 13, 3, 1)));
+        assert!(!day_only.matches(&at(0, 0, 1, 3, 5)));
+        assert!(odd_mondays.matches(&at(0, 0, 3, 3, 1)));
+        assert!(!odd_mondays.matches(&at(0, 0, 4, 3, 1)));
+        assert!(!odd_mondays.matches(&at(0, 0, 3, 3, 2)));
+    }
+
+    #[test]
+    fn matches_sunday_as_0_and_7() {
+        assert!(schedule("0 0 * * 7").matches(&at(0, 0, 1, 3, 0)));
+        assert!(schedule("0 0 * * 0").matches(&at(0, 0, 1, 3, 0)));
+        assert!(schedule("0 0 * * 5-7").matches(&at(0, 0, 1, 3, 0)));
+    }
+
+    #[test]
+    fn expands_shorthands() {
+        let monthly = schedule("@monthly");
+
+        assert!(monthly.matches(&at(0, 0, 1, 7, 4)));
+        assert!(!monthly.matches(&at(0, 0, 2, 7, 5)));
+        assert_eq!("@monthly", monthly.to_string());
+    }
+
+    #[test]
+    fn rejects_invalid_schedules() {
+        for input in &[
+            "* * * *",
+            "* * * * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "* * * 13 *",
+            "* * * * 8",
+            "5-1 * * * *",
+            "*/0 * * * *",
+            "a * * * *",
+            "@often",
+        ] {
+            assert!(
+                input.parse::<Schedule>().is_err(),
+                "{} should be invalid",
+                input
+            );
+        }
+    }
+}
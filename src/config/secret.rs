@@ -0,0 +1,46 @@
+use sha2::{Digest, Sha256};
+
+const SHA256_PREFIX: &str = "sha256:";
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+///
+/// Compares two byte slices in constant time (with respect to their contents).
+///
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+///
+/// Hashes a secret so that it can be stored in `tokens.toml`.
+///
+pub(crate) fn hash_secret(secret: &str) -> String {
+    let digest = Sha256::digest(secret.as_bytes());
+
+    format!("{}{}", SHA256_PREFIX, to_hex(&digest))
+}
+
+pub(crate) fn is_hashed(stored: &str) -> bool {
+    stored.starts_with(SHA256_PREFIX)
+}
+
+///
+/// Checks a secret against the stored (either hashed or plaintext) secret.
+///
+pub(crate) fn verify_secret(stored: &str, secret: &str) -> bool {
+    if is_hashed(stored) {
+        // the digest may have been generated by other tools, which might print it in uppercase
+        let expected = stored[SHA256_PREFIX.len()..].to_ascii_lowercase();
+        let actual = to_hex(&Sha256::digest(secret.as_bytes()));
+
+        constant_time_eq(expected.as_bytes(), actual.as_bytes())
+    } else {
+        constant_time_eq(stored.as_bytes(), secret.as_bytes())
+    }
+}
@@ -0,0 +1,332 @@
+use super::{
+    Clean, Config, ConfigError, LimitsConfig, Project, Projects, RetentionConfig, Script,
+    SourceConfig, Tokens, Workspace,
+};
+use crate::user::Credentials;
+use glob::Pattern;
+use std::collections::HashSet;
+use std::path::{Component, Path};
+use std::slice::SliceConcatExt;
+
+macro invalid($($arg:tt)*) {
+    return Err(ConfigError::Invalid(format!($($arg)*)));
+}
+
+pub(super) fn validate(config: &Config) -> Result<(), ConfigError> {
+    validate_tokens(&config.tokens)?;
+    validate_retention("the main config", &config.main.retention)?;
+
+    for (name, project) in &config.projects {
+        validate_retention(&format!("project {}", name), &project.retention)?;
+        validate_workspace(name, project)?;
+
+        if let Some(ref source) = project.source {
+            validate_source(name, source)?;
+        }
+
+        validate_artifacts(name, &project.artifacts)?;
+        validate_lock_groups(name, &project.lock_groups)?;
+
+        if let Some(ref approval) = project.approval {
+            if approval.timeout_mins == Some(0) {
+                invalid!(
+                    "Approval timeout of project {} must be greater than 0",
+                    name
+                );
+            }
+        }
+
+        validate_user(name, project)?;
+        validate_limits(name, &project.limits)?;
+
+        let phases = [
+            &project.scripts,
+            &project.on_success,
+            &project.on_failure,
+            &project.finally,
+        ];
+
+        for script in phases.iter().flat_map(|scripts| scripts.iter()) {
+            validate_script(name, script)?;
+        }
+    }
+
+    validate_triggers(&config.projects)?;
+
+    Ok(())
+}
+
+fn validate_tokens(tokens: &Tokens) -> Result<(), ConfigError> {
+    for (name, token) in tokens {
+        for pattern in &token.access {
+            if let Err(err) = Pattern::new(pattern) {
+                invalid!(
+                    "Token {} has an invalid access pattern {}: {}",
+                    name,
+                    pattern,
+                    err
+                );
+            }
+        }
+
+        if token.expires_at.is_some() && token.expires_at().is_none() {
+            invalid!("Token {} has an invalid expiry date", name);
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_retention(owner: &str, retention: &RetentionConfig) -> Result<(), ConfigError> {
+    let limits = [
+        ("max_age_days", retention.max_age_days),
+        (
+            "max_jobs",
+            retention.max_jobs.map(|max_jobs| max_jobs as u64),
+        ),
+        ("max_size_mb", retention.max_size_mb),
+        ("compress_after_days", retention.compress_after_days),
+    ];
+
+    for &(field, value) in &limits {
+        if value == Some(0) {
+            invalid!(
+                "Retention limit {} of {} must be greater than 0",
+                field,
+                owner
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_workspace(name: &str, project: &Project) -> Result<(), ConfigError> {
+    match project.workspace {
+        Workspace::Path(ref path) if !path.is_absolute() => {
+            invalid!(
+                "Project {} has a relative workspace path {}",
+                name,
+                path.display()
+            );
+        }
+        Workspace::Temp if project.clean != Clean::Never => {
+            invalid!("Project {} can't clean a temporary workspace", name);
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+fn validate_artifacts(name: &str, artifacts: &[String]) -> Result<(), ConfigError> {
+    for pattern in artifacts {
+        if let Err(err) = Pattern::new(pattern) {
+            invalid!(
+                "Project {} has an invalid artifact pattern {}: {}",
+                name,
+                pattern,
+                err
+            );
+        }
+
+        // patterns are relative to the workspace and must not leave it
+        let escapes = Path::new(pattern)
+            .components()
+            .any(|component| match component {
+                Component::Normal(..) | Component::CurDir => false,
+                _ => true,
+            });
+
+        if escapes {
+            invalid!(
+                "Artifact pattern {} of project {} must be relative to the workspace",
+                pattern,
+                name
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_lock_groups(name: &str, groups: &[String]) -> Result<(), ConfigError> {
+    for group in groups {
+        // lock groups are used as file names
+        let valid = !group.is_empty()
+            && group
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+
+        if !valid {
+            invalid!("Project {} has an invalid lock group {}", name, group);
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_source(name: &str, source: &SourceConfig) -> Result<(), ConfigError> {
+    if source.repository.is_empty() {
+        invalid!("Project {} has an empty source repository", name);
+    }
+
+    if source.git_ref.as_ref().map_or(false, String::is_empty) {
+        invalid!("Project {} has an empty source ref", name);
+    }
+
+    if source.depth == Some(0) {
+        invalid!("Source depth of project {} must be greater than 0", name);
+    }
+
+    Ok(())
+}
+
+fn validate_user(name: &str, project: &Project) -> Result<(), ConfigError> {
+    if project.user.is_none() && (project.group.is_some() || !project.groups.is_empty()) {
+        invalid!("Project {} sets a group without setting a user", name);
+    }
+
+    if let Err(err) = Credentials::for_project(project) {
+        invalid!("Project {} can't run as the configured user: {}", name, err);
+    }
+
+    Ok(())
+}
+
+fn validate_limits(name: &str, limits: &LimitsConfig) -> Result<(), ConfigError> {
+    let values = [
+        ("cpu_time_secs", limits.cpu_time_secs),
+        ("address_space_mb", limits.address_space_mb),
+        ("open_files", limits.open_files),
+        ("processes", limits.processes),
+        ("memory_mb", limits.memory_mb),
+        ("cpu_percent", limits.cpu_percent),
+    ];
+
+    for &(field, value) in &values {
+        if value == Some(0) {
+            invalid!("Limit {} of project {} must be greater than 0", field, name);
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_script(name: &str, script: &Script) -> Result<(), ConfigError> {
+    match (script.command.is_empty(), &script.run) {
+        (true, &None) => {
+            invalid!(
+                "Script {} of project {} needs either a command or run",
+                script.display_name(),
+                name
+            );
+        }
+        (false, &Some(..)) => {
+            invalid!(
+                "Script {} of project {} can't have both a command and run",
+                script.display_name(),
+                name
+            );
+        }
+        _ => {}
+    }
+
+    if script.retries == 0 && (script.retry_delay.is_some() || !script.retry_on.is_empty()) {
+        invalid!(
+            "Script {} of project {} sets retry_delay or retry_on without retries",
+            script.display_name(),
+            name
+        );
+    }
+
+    match script.shell {
+        Some(..) if script.run.is_none() => {
+            invalid!(
+                "Script {} of project {} sets a shell without run",
+                script.display_name(),
+                name
+            );
+        }
+        Some(ref shell) if shell.is_empty() => {
+            invalid!(
+                "Script {} of project {} has an empty shell",
+                script.display_name(),
+                name
+            );
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+fn triggered_projects(project: &Project) -> impl Iterator<Item = &String> {
+    project
+        .on_success_trigger
+        .iter()
+        .chain(&project.on_failure_trigger)
+}
+
+///
+/// Checks that triggered projects exist and that projects don't trigger each other in a cycle,
+/// which would run jobs forever.
+///
+fn validate_triggers(projects: &Projects) -> Result<(), ConfigError> {
+    for (name, project) in projects {
+        for downstream in triggered_projects(project) {
+            if !projects.contains_key(downstream) {
+                invalid!("Project {} triggers unknown project {}", name, downstream);
+            }
+        }
+    }
+
+    let mut checked = HashSet::new();
+
+    for name in projects.keys() {
+        if let Some(cycle) = find_cycle(projects, name, &mut Vec::new(), &mut checked) {
+            invalid!(
+                "Projects trigger each other in a cycle: {}",
+                cycle.join(" -> ")
+            );
+        }
+    }
+
+    Ok(())
+}
+
+///
+/// Searches the projects triggered by a project depth-first, returning the first cycle found.
+/// `checked` holds the projects that are known to not lead into a cycle.
+///
+fn find_cycle<'a>(
+    projects: &'a Projects,
+    name: &'a str,
+    path: &mut Vec<&'a str>,
+    checked: &mut HashSet<&'a str>,
+) -> Option<Vec<&'a str>> {
+    if let Some(start) = path.iter().position(|&visited| visited == name) {
+        let mut cycle = path[start..].to_vec();
+
+        cycle.push(name);
+
+        return Some(cycle);
+    }
+
+    if checked.contains(name) {
+        return None;
+    }
+
+    path.push(name);
+
+    for downstream in triggered_projects(&projects[name]) {
+        if let Some(cycle) = find_cycle(projects, downstream, path, checked) {
+            return Some(cycle);
+        }
+    }
+
+    path.pop();
+    checked.insert(name);
+
+    None
+}
@@ -1,7 +1,8 @@
-use fs2::FileExt;
+use fs2::{lock_contended_error, FileExt};
 use std::fs::File;
 use std::io::{self, Read, Seek, SeekFrom, Write};
 
+#[derive(Debug)]
 pub(crate) struct FileLock {
     f: File,
 }
@@ -13,6 +14,17 @@ impl FileLock {
         Ok(FileLock { f })
     }
 
+    ///
+    /// Locks the file without blocking. Returns `None` if it is locked by someone else.
+    ///
+    pub(crate) fn try_exclusive(f: File) -> io::Result<Option<Self>> {
+        match f.try_lock_exclusive() {
+            Ok(()) => Ok(Some(FileLock { f })),
+            Err(ref err) if err.kind() == lock_contended_error().kind() => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
     pub(crate) fn file(&self) -> &File {
         &self.f
     }
@@ -1,11 +1,15 @@
 use byteorder::{NativeEndian, ReadBytesExt, WriteBytesExt};
-use std::fs::{DirBuilder, File, OpenOptions};
-use std::io::{self, Seek, SeekFrom};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
 mod flock;
 
-use self::flock::FileLock;
+pub(crate) use self::flock::FileLock;
 
 const LOG_PATH: &str = env!("TOBY_LOG_PATH");
 const RUNTIME_PATH: &str = env!("TOBY_RUNTIME_PATH");
@@ -20,15 +24,43 @@ fn ensure_parent(path: &Path) -> io::Result<()> {
 }
 
 fn get_job_id_path(project_name: &str) -> PathBuf {
-    let mut path = PathBuf::from(RUNTIME_PATH);
+    let mut path = job_archive_dir(project_name);
 
-    path.push("jobs");
-    path.push(project_name);
     path.push("next_id");
 
     path
 }
 
+fn get_pruned_job_id_path(project_name: &str) -> PathBuf {
+    let mut path = job_archive_dir(project_name);
+
+    path.push("pruned_id");
+
+    path
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+fn remove_dir_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_dir_all(path) {
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+fn file_size(path: &Path) -> io::Result<u64> {
+    match fs::metadata(path) {
+        Ok(metadata) => Ok(metadata.len()),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(err) => Err(err),
+    }
+}
+
 pub fn job_log_path(project_name: &str, job_id: u64) -> PathBuf {
     let mut path = PathBuf::from(LOG_PATH);
 
@@ -40,11 +72,26 @@ pub fn job_log_path(project_name: &str, job_id: u64) -> PathBuf {
     path
 }
 
-fn job_archive_path(project_name: &str, job_id: u64) -> PathBuf {
+fn compressed_job_log_path(project_name: &str, job_id: u64) -> PathBuf {
+    let mut path = job_log_path(project_name, job_id);
+
+    path.set_extension("log.gz");
+
+    path
+}
+
+fn job_archive_dir(project_name: &str) -> PathBuf {
     let mut path = PathBuf::from(RUNTIME_PATH);
 
     path.push("jobs");
     path.push(project_name);
+
+    path
+}
+
+fn job_archive_path(project_name: &str, job_id: u64) -> PathBuf {
+    let mut path = job_archive_dir(project_name);
+
     path.push(job_id.to_string());
 
     path.set_extension("toml");
@@ -52,6 +99,150 @@ fn job_archive_path(project_name: &str, job_id: u64) -> PathBuf {
     path
 }
 
+///
+/// The directory the artifacts of a job are copied to.
+///
+pub(crate) fn job_artifacts_path(project_name: &str, job_id: u64) -> PathBuf {
+    let mut path = job_archive_dir(project_name);
+
+    path.push(format!("{}.artifacts", job_id));
+
+    path
+}
+
+fn dir_size(path: &Path) -> io::Result<u64> {
+    let dir = match fs::read_dir(path) {
+        Ok(dir) => dir,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err),
+    };
+
+    let mut size = 0;
+
+    for entry in dir {
+        let entry = entry?;
+
+        size += if entry.file_type()?.is_dir() {
+            dir_size(&entry.path())?
+        } else {
+            entry.metadata()?.len()
+        };
+    }
+
+    Ok(size)
+}
+
+///
+/// The persistent workspace of a project.
+///
+pub(crate) fn workspace_path(project_name: &str) -> PathBuf {
+    let mut path = PathBuf::from(RUNTIME_PATH);
+
+    path.push("workspaces");
+    path.push(project_name);
+
+    path
+}
+
+fn workspace_lock_path(project_name: &str) -> PathBuf {
+    let mut path = PathBuf::from(RUNTIME_PATH);
+
+    path.push("workspaces");
+    path.push(format!("{}.lock", project_name));
+
+    path
+}
+
+///
+/// The file backing a lock group, shared by all projects that list the group.
+///
+fn lock_group_path(group: &str) -> PathBuf {
+    let mut path = PathBuf::from(RUNTIME_PATH);
+
+    path.push("locks");
+    path.push(format!("{}.lock", group));
+
+    path
+}
+
+fn open_lock_group(group: &str) -> io::Result<File> {
+    let path = lock_group_path(group);
+
+    ensure_parent(&path)?;
+
+    OpenOptions::new().create(true).write(true).open(path)
+}
+
+///
+/// The lock groups in the order they are locked in. A consistent order prevents
+/// two jobs from each holding a lock the other one waits for.
+///
+fn sorted_groups(groups: &[String]) -> Vec<&str> {
+    let mut groups: Vec<_> = groups.iter().map(String::as_str).collect();
+
+    groups.sort();
+    groups.dedup();
+
+    groups
+}
+
+///
+/// Locks all of the lock groups, waiting until they are available.
+///
+pub(crate) fn lock_groups(groups: &[String]) -> io::Result<Vec<FileLock>> {
+    sorted_groups(groups)
+        .into_iter()
+        .map(|group| FileLock::exclusive(open_lock_group(group)?))
+        .collect()
+}
+
+///
+/// Locks all of the lock groups if none of them is held by another job.
+/// Returns `None` (without holding any of the locks) otherwise.
+///
+pub(crate) fn try_lock_groups(groups: &[String]) -> io::Result<Option<Vec<FileLock>>> {
+    let mut locks = Vec::new();
+
+    for group in sorted_groups(groups) {
+        match FileLock::try_exclusive(open_lock_group(group)?)? {
+            Some(lock) => locks.push(lock),
+            None => return Ok(None),
+        }
+    }
+
+    Ok(Some(locks))
+}
+
+///
+/// Locks the workspace of a project, waiting for other jobs of the project to release it.
+///
+pub(crate) fn lock_workspace(project_name: &str) -> io::Result<FileLock> {
+    let path = workspace_lock_path(project_name);
+
+    ensure_parent(&path)?;
+
+    let file = OpenOptions::new().create(true).write(true).open(path)?;
+
+    FileLock::exclusive(file)
+}
+
+///
+/// Removes the contents of a directory, but not the directory itself.
+///
+pub(crate) fn clear_dir(path: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+
+        if entry.file_type()?.is_dir() {
+            fs::remove_dir_all(entry.path())?;
+        } else {
+            fs::remove_file(entry.path())?;
+        }
+    }
+
+    Ok(())
+}
+
 pub(crate) fn get_telegram_chat_id_path() -> PathBuf {
     let mut path = PathBuf::from(RUNTIME_PATH);
 
@@ -68,6 +259,19 @@ pub(crate) fn get_job_archive_file(project_name: &str, job_id: u64) -> io::Resul
     OpenOptions::new().create(true).write(true).open(path)
 }
 
+///
+/// Opens the archive of a job for reading. Returns `None` if the job has not been archived (yet).
+///
+pub(crate) fn open_job_archive(project_name: &str, job_id: u64) -> io::Result<Option<File>> {
+    let path = job_archive_path(project_name, job_id);
+
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    File::open(path).map(Some)
+}
+
 ///
 /// Determines and creates the log file for a job.
 ///
@@ -83,6 +287,141 @@ pub(crate) fn get_job_log(project_name: &str, job_id: u64) -> io::Result<File> {
         .open(path)
 }
 
+///
+/// Opens the log of a job for reading, transparently decompressing it if it has been compressed.
+///
+pub(crate) fn open_job_log(project_name: &str, job_id: u64) -> io::Result<Box<Read + Send>> {
+    let path = job_log_path(project_name, job_id);
+
+    if path.exists() {
+        return Ok(Box::new(File::open(path)?));
+    }
+
+    let file = File::open(compressed_job_log_path(project_name, job_id))?;
+
+    Ok(Box::new(GzDecoder::new(file)))
+}
+
+///
+/// Replaces the log of a job with a gzip compressed copy.
+/// Returns `false` if there is no uncompressed log.
+///
+pub(crate) fn compress_job_log(project_name: &str, job_id: u64) -> io::Result<bool> {
+    let path = job_log_path(project_name, job_id);
+
+    if !path.exists() {
+        return Ok(false);
+    }
+
+    let compressed_path = compressed_job_log_path(project_name, job_id);
+    let mut temp_path = compressed_path.clone();
+
+    temp_path.set_extension("gz.tmp");
+
+    {
+        let mut encoder = GzEncoder::new(File::create(&temp_path)?, Compression::default());
+
+        io::copy(&mut File::open(&path)?, &mut encoder)?;
+
+        encoder.finish()?.sync_all()?;
+    }
+
+    fs::rename(temp_path, compressed_path)?;
+    fs::remove_file(path)?;
+
+    Ok(true)
+}
+
+///
+/// Lists the ids of all archived (i.e. finished) jobs of a project in ascending order.
+///
+pub(crate) fn archived_job_ids(project_name: &str) -> io::Result<Vec<u64>> {
+    let dir = match fs::read_dir(job_archive_dir(project_name)) {
+        Ok(dir) => dir,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+
+    let mut ids = Vec::new();
+
+    for entry in dir {
+        let path = entry?.path();
+
+        if path
+            .extension()
+            .map_or(false, |extension| extension == "toml")
+        {
+            if let Some(id) = path.file_stem().and_then(|stem| stem.to_str()) {
+                if let Ok(id) = id.parse() {
+                    ids.push(id);
+                }
+            }
+        }
+    }
+
+    ids.sort();
+
+    Ok(ids)
+}
+
+///
+/// The combined size of a job's archive, log and artifacts in bytes.
+///
+pub(crate) fn job_size(project_name: &str, job_id: u64) -> io::Result<u64> {
+    Ok(file_size(&job_archive_path(project_name, job_id))?
+        + file_size(&job_log_path(project_name, job_id))?
+        + file_size(&compressed_job_log_path(project_name, job_id))?
+        + dir_size(&job_artifacts_path(project_name, job_id))?)
+}
+
+///
+/// The time at which a job has been archived.
+///
+pub(crate) fn job_archived_at(project_name: &str, job_id: u64) -> io::Result<SystemTime> {
+    fs::metadata(job_archive_path(project_name, job_id))?.modified()
+}
+
+///
+/// Deletes the archive, log and artifacts of a job.
+///
+pub(crate) fn remove_job(project_name: &str, job_id: u64) -> io::Result<()> {
+    remove_if_exists(&job_log_path(project_name, job_id))?;
+    remove_if_exists(&compressed_job_log_path(project_name, job_id))?;
+    remove_dir_if_exists(&job_artifacts_path(project_name, job_id))?;
+    remove_if_exists(&job_archive_path(project_name, job_id))
+}
+
+///
+/// Determines the highest job id that has been pruned for a project.
+///
+pub(crate) fn pruned_job_id(project_name: &str) -> io::Result<Option<u64>> {
+    let path = get_pruned_job_id_path(project_name);
+
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let mut file = File::open(path)?;
+
+    Ok(Some(file.read_u64::<NativeEndian>()?))
+}
+
+pub(crate) fn write_pruned_job_id(project_name: &str, job_id: u64) -> io::Result<()> {
+    let path = get_pruned_job_id_path(project_name);
+
+    ensure_parent(&path)?;
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(path)?;
+
+    file.write_u64::<NativeEndian>(job_id)?;
+
+    Ok(())
+}
+
 ///
 /// Determines the next job id for a project and increments the counter.
 ///
@@ -115,6 +454,23 @@ pub(crate) fn next_job_id(project_name: &str) -> io::Result<u64> {
     Ok(next_id)
 }
 
+///
+/// Determines the id that was last handed out for a project without incrementing the counter.
+///
+pub(crate) fn last_job_id(project_name: &str) -> io::Result<Option<u64>> {
+    let path = get_job_id_path(project_name);
+
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let mut file = File::open(path)?;
+
+    let next_id = file.read_u64::<NativeEndian>().unwrap_or(1);
+
+    Ok(if next_id > 1 { Some(next_id - 1) } else { None })
+}
+
 pub(crate) fn get_telegram_chat_id() -> io::Result<Option<i64>> {
     let path = get_telegram_chat_id_path();
 
@@ -140,3 +496,39 @@ pub(crate) fn write_telegram_chat_id(chat_id: i64) -> io::Result<()> {
 
     Ok(())
 }
+
+fn queue_path() -> PathBuf {
+    let mut path = PathBuf::from(RUNTIME_PATH);
+
+    path.push("queue.toml");
+
+    path
+}
+
+///
+/// Saves the jobs that were still queued when tobyd stopped.
+///
+pub(crate) fn write_queue(contents: &str) -> io::Result<()> {
+    let path = queue_path();
+
+    ensure_parent(&path)?;
+
+    fs::write(path, contents)
+}
+
+///
+/// Reads and removes the jobs that were still queued when tobyd stopped.
+///
+pub(crate) fn take_queue() -> io::Result<Option<String>> {
+    let path = queue_path();
+
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let contents = fs::read_to_string(&path)?;
+
+    fs::remove_file(path)?;
+
+    Ok(Some(contents))
+}
@@ -12,7 +12,10 @@
 
 extern crate byteorder;
 extern crate clap;
+extern crate flate2;
 extern crate fs2;
+extern crate glob;
+extern crate libc;
 extern crate nanoid;
 extern crate reqwest;
 extern crate rocket;
@@ -20,15 +23,22 @@ extern crate rocket_contrib;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+extern crate serde_json;
+extern crate sha2;
 extern crate tempdir;
 extern crate toml;
+extern crate toml_edit;
 
 pub mod cli;
 pub(crate) mod config;
 pub(crate) mod fs;
+pub(crate) mod retention;
+pub(crate) mod scheduler;
 pub(crate) mod server;
+pub(crate) mod shutdown;
 pub mod telegram;
 pub(crate) mod time;
+pub(crate) mod user;
 pub(crate) mod worker;
 
 pub macro clap_app() {{
@@ -0,0 +1,135 @@
+use crate::config::{Config, RetentionConfig};
+use crate::fs::{
+    archived_job_ids, compress_job_log, job_archived_at, job_size, pruned_job_id, remove_job,
+    write_pruned_job_id,
+};
+use crate::status;
+use std::fmt;
+use std::io;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+const BYTES_PER_MB: u64 = 1024 * 1024;
+const PRUNE_INTERVAL_SECS: u64 = 60 * 60;
+
+#[derive(Debug, Default)]
+pub(crate) struct PruneReport {
+    pub(crate) removed: usize,
+    pub(crate) compressed: usize,
+}
+
+impl fmt::Display for PruneReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "removed {} jobs, compressed {} logs",
+            self.removed, self.compressed
+        )
+    }
+}
+
+fn is_older_than(time: SystemTime, days: u64, now: SystemTime) -> bool {
+    now.duration_since(time)
+        .map(|age| age > Duration::from_secs(days * SECONDS_PER_DAY))
+        .unwrap_or(false)
+}
+
+fn mark_pruned(project_name: &str, job_id: u64) -> io::Result<()> {
+    match pruned_job_id(project_name)? {
+        Some(pruned_id) if pruned_id >= job_id => Ok(()),
+        _ => write_pruned_job_id(project_name, job_id),
+    }
+}
+
+///
+/// Applies a retention policy to the finished jobs of a project.
+///
+/// Jobs are checked from newest to oldest. Once a job exceeds one of the limits,
+/// it is removed along with all older jobs, so the remaining jobs are always the most recent ones.
+///
+fn prune_project(
+    project_name: &str,
+    retention: &RetentionConfig,
+    now: SystemTime,
+) -> io::Result<PruneReport> {
+    let mut report = PruneReport::default();
+    let mut total_size = 0;
+    let mut expired = false;
+
+    for (index, &job_id) in archived_job_ids(project_name)?.iter().rev().enumerate() {
+        let archived_at = job_archived_at(project_name, job_id)?;
+
+        total_size += job_size(project_name, job_id)?;
+
+        if !expired {
+            expired = retention
+                .max_jobs
+                .map_or(false, |max_jobs| index >= max_jobs)
+                || retention
+                    .max_age_days
+                    .map_or(false, |days| is_older_than(archived_at, days, now))
+                || retention
+                    .max_size_mb
+                    .map_or(false, |max_size| total_size > max_size * BYTES_PER_MB);
+
+            if expired {
+                // lets the api tell pruned jobs apart from queued ones
+                mark_pruned(project_name, job_id)?;
+            }
+        }
+
+        if expired {
+            remove_job(project_name, job_id)?;
+            report.removed += 1;
+        } else if retention
+            .compress_after_days
+            .map_or(false, |days| is_older_than(archived_at, days, now))
+            && compress_job_log(project_name, job_id)?
+        {
+            report.compressed += 1;
+        }
+    }
+
+    Ok(report)
+}
+
+///
+/// Applies the retention policies of all projects. Projects without a policy are skipped.
+///
+pub(crate) fn prune_all(config: &Config) -> Vec<(&str, io::Result<PruneReport>)> {
+    let now = SystemTime::now();
+    let mut names: Vec<_> = config.projects.keys().collect();
+
+    names.sort();
+
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let retention = config.projects[name].retention(&config.main);
+
+            if retention.is_empty() {
+                None
+            } else {
+                Some((name.as_str(), prune_project(name, &retention, now)))
+            }
+        })
+        .collect()
+}
+
+///
+/// Periodically applies the retention policies of all projects. Never returns.
+///
+pub(crate) fn start_pruning(config: &Config) {
+    loop {
+        for (name, result) in prune_all(config) {
+            match result {
+                Ok(ref report) if report.removed == 0 && report.compressed == 0 => {}
+                Ok(report) => status!("Pruned jobs of project {}: {}", name, report),
+                Err(err) => status!("Unable to prune jobs of project {}: {}", name, err),
+            }
+        }
+
+        thread::sleep(Duration::from_secs(PRUNE_INTERVAL_SECS));
+    }
+}
@@ -0,0 +1,178 @@
+use crate::config::{Config, Schedule};
+use crate::fs::next_job_id;
+use crate::status;
+use crate::time::{now, CalendarTime};
+use crate::worker::{
+    read_job_archive, Job, JobId, JobQueue, JobTrigger, Parameters, PushError, Revision,
+    WorkerState,
+};
+use std::collections::HashMap;
+use std::thread;
+use std::time::Duration;
+
+const SECS_PER_MINUTE: u64 = 60;
+
+///
+/// Whether a job is still waiting in the queue. Jobs that have finished (or have been
+/// cancelled) have an archive.
+///
+fn is_queued(state: &WorkerState, project_name: &str, job_id: JobId) -> bool {
+    if state.is_running(project_name, job_id) {
+        return false;
+    }
+
+    match read_job_archive(project_name, job_id) {
+        Ok(archive) => archive.is_none(),
+        Err(_) => false,
+    }
+}
+
+///
+/// Returns the first schedule of the project that is due in one of the given minutes.
+///
+fn due_schedule<'a>(schedules: &'a [Schedule], minutes: &[u64]) -> Option<&'a Schedule> {
+    schedules.iter().find(|schedule| {
+        minutes.iter().any(|&minute| {
+            schedule.matches(&CalendarTime::from_timestamp(minute * SECS_PER_MINUTE))
+        })
+    })
+}
+
+///
+/// Enqueues jobs for projects with a `schedule` when it is due. Never returns.
+///
+/// A run is skipped if the previous scheduled job of the project is still queued.
+///
+pub(crate) fn start_scheduler(config: &Config, queue: &JobQueue, state: &WorkerState) {
+    let mut scheduled_jobs: HashMap<&str, JobId> = HashMap::new();
+    let mut last_minute = now() / SECS_PER_MINUTE;
+
+    loop {
+        thread::sleep(Duration::from_secs(
+            SECS_PER_MINUTE - now() % SECS_PER_MINUTE,
+        ));
+
+        let minute = now() / SECS_PER_MINUTE;
+
+        // minutes that passed while the scheduler was behind (e.g. after a suspend) are
+        // caught up on, but each project is only triggered once
+        let minutes: Vec<u64> = (last_minute + 1..minute + 1).collect();
+
+        last_minute = minute.max(last_minute);
+
+        for (project_name, project) in &config.projects {
+            let schedule = match due_schedule(&project.schedule, &minutes) {
+                Some(schedule) => schedule,
+                None => continue,
+            };
+
+            if let Some(&job_id) = scheduled_jobs.get(project_name.as_str()) {
+                if is_queued(state, project_name, job_id) {
+                    status!(
+                        "Skipping scheduled job for {}, job #{} is still queued",
+                        project_name,
+                        job_id
+                    );
+                    continue;
+                }
+            }
+
+            let pushed = queue.push(|| {
+                Ok(Job {
+                    id: next_job_id(project_name)?,
+                    project: project_name.clone(),
+                    priority: project.priority,
+                    trigger: JobTrigger::Schedule {
+                        schedule: schedule.to_string(),
+                    },
+                    revision: Revision::default(),
+                    parameters: Parameters::new(),
+                })
+            });
+
+            match pushed {
+                Ok(job_id) => {
+                    scheduled_jobs.insert(project_name, job_id);
+                }
+                // tobyd is stopping
+                Err(PushError::Closed) => return,
+                Err(PushError::Io(err)) => {
+                    status!("Unable to schedule job for {}: {}", project_name, err)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time::parse_rfc3339;
+
+    fn minutes(from: &str, to: &str) -> Vec<u64> {
+        let from = parse_rfc3339(from).unwrap() / SECS_PER_MINUTE;
+        let to = parse_rfc3339(to).unwrap() / SECS_PER_MINUTE;
+
+        (from..to + 1).collect()
+    }
+
+    fn is_due(schedules: &[Schedule], from: &str, to: &str) -> bool {
+        due_schedule(schedules, &minutes(from, to)).is_some()
+    }
+
+    fn schedules(inputs: &[&str]) -> Vec<Schedule> {
+        inputs.iter().map(|input| input.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn finds_schedule_due_in_minutes() {
+        let schedules = schedules(&["0 3 * * *", "30 12 * * *"]);
+
+        let due = due_schedule(
+            &schedules,
+            &minutes("2018-07-14T12:29:00Z", "2018-07-14T12:31:00Z"),
+        );
+
+        assert_eq!(
+            Some("30 12 * * *".to_string()),
+            due.map(ToString::to_string)
+        );
+        assert!(!is_due(
+            &schedules,
+            "2018-07-14T12:31:00Z",
+            "2018-07-14T12:40:00Z"
+        ));
+    }
+
+    #[test]
+    fn catches_up_across_the_end_of_a_month() {
+        let schedules = schedules(&["0 0 1 * *"]);
+
+        assert!(is_due(
+            &schedules,
+            "2020-02-29T23:58:00Z",
+            "2020-03-01T00:01:00Z"
+        ));
+        assert!(!is_due(
+            &schedules,
+            "2020-02-29T00:00:00Z",
+            "2020-02-29T23:59:00Z"
+        ));
+    }
+
+    #[test]
+    fn skips_days_missing_from_a_month() {
+        let schedules = schedules(&["0 0 31 * *"]);
+
+        assert!(!is_due(
+            &schedules,
+            "2018-04-30T23:00:00Z",
+            "2018-05-01T01:00:00Z"
+        ));
+        assert!(is_due(
+            &schedules,
+            "2018-05-30T23:00:00Z",
+            "2018-05-31T01:00:00Z"
+        ));
+    }
+}
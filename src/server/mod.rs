@@ -1,66 +1,360 @@
 use self::token::ValidToken;
-use super::config::Config;
+use super::config::{Config, Permission};
 use super::status;
-use super::worker::{Job, JobTrigger};
-use crate::fs::next_job_id;
-use crate::worker::WorkerSender;
+use super::worker::{is_valid_parameter_name, Job, JobTrigger, Parameters, Revision};
+use crate::fs::{job_artifacts_path, last_job_id, next_job_id, open_job_log, pruned_job_id};
+use crate::worker::{
+    cancel_job, read_job_archive, read_job_log, ApprovalError, ArchivedJob, CancelError, JobId,
+    JobQueue, LogRecord, PushError, WorkerState,
+};
 use rocket::{self, State};
 use rocket::config::{ConfigBuilder, Environment};
 use rocket::fairing::AdHoc;
-use rocket::http::Status;
-use rocket::response::Failure;
+use rocket::http::{ContentType, Status};
+use rocket::response::{Content, Failure, NamedFile, Stream};
 use rocket_contrib::Json;
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::sync::Arc;
 
 mod token;
 
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct CreateJobRequest {
+    #[serde(rename = "ref")]
+    git_ref: Option<String>,
+    commit: Option<String>,
+    #[serde(default)]
+    parameters: Parameters,
+    priority: Option<i32>,
+}
+
 #[derive(Serialize, Deserialize)]
 struct CreateJobResponse {
     id: u64,
 }
 
+#[derive(Serialize)]
+struct QueuedJobResponse {
+    /// Starting at 1 for the job that runs next
+    position: usize,
+    project: String,
+    id: JobId,
+    priority: i32,
+    trigger: JobTrigger,
+}
+
+#[derive(Serialize)]
+struct QueueResponse {
+    jobs: Vec<QueuedJobResponse>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum JobStatus {
+    Queued,
+    AwaitingApproval,
+    Running,
+    Successful,
+    Failed,
+    Cancelled,
+    Rejected,
+    Interrupted,
+}
+
+#[derive(Serialize)]
+struct JobStatusResponse {
+    id: u64,
+    status: JobStatus,
+    archive: Option<ArchivedJob>,
+}
+
 impl CreateJobResponse {
     fn new(id: u64) -> Self {
         CreateJobResponse { id }
     }
 }
 
-#[post("/v1/jobs/<project_name>")]
+impl JobStatus {
+    fn from_archive(archive: &ArchivedJob) -> Self {
+        if archive.cancelled {
+            JobStatus::Cancelled
+        } else if archive.is_rejected() {
+            JobStatus::Rejected
+        } else if archive.interrupted {
+            JobStatus::Interrupted
+        } else if archive.successful {
+            JobStatus::Successful
+        } else {
+            JobStatus::Failed
+        }
+    }
+}
+
+///
+/// Checks that the project exists and that the token has the given permission for it.
+///
+fn authorize(
+    token: &ValidToken,
+    config: &Config,
+    project_name: &str,
+    permission: Permission,
+) -> Result<(), Failure> {
+    if config.projects.contains_key(project_name) && token.can(permission, project_name) {
+        Ok(())
+    } else {
+        Err(Failure(Status::Forbidden))
+    }
+}
+
+fn is_known_job(project_name: &str, job_id: JobId) -> Result<bool, Failure> {
+    match last_job_id(project_name) {
+        Ok(last_id) => Ok(last_id.map_or(false, |last_id| job_id <= last_id)),
+        Err(_) => Err(Failure(Status::InternalServerError)),
+    }
+}
+
+fn is_pruned_job(project_name: &str, job_id: JobId) -> Result<bool, Failure> {
+    match pruned_job_id(project_name) {
+        Ok(pruned_id) => Ok(pruned_id.map_or(false, |pruned_id| job_id <= pruned_id)),
+        Err(_) => Err(Failure(Status::InternalServerError)),
+    }
+}
+
+#[post("/v1/jobs/<project_name>", data = "<request>")]
 fn create_job(
     token: ValidToken,
-    tx: State<WorkerSender>,
+    queue: State<Arc<JobQueue>>,
     config: State<Config>,
     project_name: String,
+    request: Option<Json<CreateJobRequest>>,
 ) -> Result<Json<CreateJobResponse>, Failure> {
-    let projects = &config.projects;
-
-    match projects
-        .get(&project_name)
-        .filter(|_| token.can_access(&project_name))
-    {
-        Some(val) => val,
-        None => return Err(Failure(Status::Forbidden)),
+    authorize(&token, &config, &project_name, Permission::Trigger)?;
+
+    let request = request.map(Json::into_inner).unwrap_or_default();
+    let revision = Revision {
+        git_ref: request.git_ref,
+        commit: request.commit,
     };
 
-    let job_id = match next_job_id(&project_name) {
-        Ok(id) => id,
+    let valid_parameters = request
+        .parameters
+        .keys()
+        .all(|name| is_valid_parameter_name(name));
+
+    if !revision.is_valid() || !valid_parameters {
+        return Err(Failure(Status::BadRequest));
+    }
+
+    // overriding the project's priority lets a job run before the jobs of other projects
+    if request.priority.is_some() {
+        authorize(&token, &config, &project_name, Permission::Prioritize)?;
+    }
+
+    let priority = request
+        .priority
+        .unwrap_or_else(|| config.projects[&project_name].priority);
+    let parameters = request.parameters;
+
+    let pushed = queue.push(|| {
+        Ok(Job {
+            id: next_job_id(&project_name)?,
+            project: project_name,
+            priority,
+            trigger: JobTrigger::Webhook {
+                token: token.token_name().into(),
+            },
+            revision,
+            parameters,
+        })
+    });
+
+    match pushed {
+        Ok(job_id) => Ok(Json(CreateJobResponse::new(job_id))),
+        // tobyd is stopping
+        Err(PushError::Closed) => Err(Failure(Status::ServiceUnavailable)),
+        Err(PushError::Io(_)) => Err(Failure(Status::InternalServerError)),
+    }
+}
+
+///
+/// Lists the queued jobs of the projects the token can read the status of, in the order they will run in.
+/// Positions are counted across all projects.
+///
+#[get("/v1/queue")]
+fn get_queue(token: ValidToken, queue: State<Arc<JobQueue>>) -> Json<QueueResponse> {
+    let jobs = queue
+        .pending()
+        .into_iter()
+        .enumerate()
+        .filter(|&(_, ref job)| token.can(Permission::ReadStatus, &job.project))
+        .map(|(index, job)| QueuedJobResponse {
+            position: index + 1,
+            project: job.project,
+            id: job.id,
+            priority: job.priority,
+            trigger: job.trigger,
+        })
+        .collect();
+
+    Json(QueueResponse { jobs })
+}
+
+#[get("/v1/jobs/<project_name>/<job_id>")]
+fn get_job(
+    token: ValidToken,
+    config: State<Config>,
+    state: State<Arc<WorkerState>>,
+    project_name: String,
+    job_id: JobId,
+) -> Result<Json<JobStatusResponse>, Failure> {
+    authorize(&token, &config, &project_name, Permission::ReadStatus)?;
+
+    let archive = match read_job_archive(&project_name, job_id) {
+        Ok(archive) => archive,
         Err(_) => return Err(Failure(Status::InternalServerError)),
     };
 
-    let job = Job {
-        id: job_id,
-        project: project_name,
-        trigger: JobTrigger::Webhook {
-            token: token.token_name().into(),
-        },
+    let status = match archive {
+        Some(ref archive) => JobStatus::from_archive(archive),
+        None if !is_known_job(&project_name, job_id)? => return Err(Failure(Status::NotFound)),
+        None if is_pruned_job(&project_name, job_id)? => return Err(Failure(Status::Gone)),
+        None if state.is_awaiting_approval(&project_name, job_id) => JobStatus::AwaitingApproval,
+        None if state.is_running(&project_name, job_id) => JobStatus::Running,
+        None => JobStatus::Queued,
     };
 
-    match tx.send(job) {
-        Ok(_) => Ok(Json(CreateJobResponse::new(job_id))),
+    Ok(Json(JobStatusResponse {
+        id: job_id,
+        status,
+        archive,
+    }))
+}
+
+#[get("/v1/jobs/<project_name>/<job_id>/log")]
+fn get_job_log(
+    token: ValidToken,
+    config: State<Config>,
+    project_name: String,
+    job_id: JobId,
+) -> Result<Content<Stream<Box<Read + Send>>>, Failure> {
+    authorize(&token, &config, &project_name, Permission::ReadLogs)?;
+
+    match open_job_log(&project_name, job_id) {
+        Ok(log) => Ok(Content(ContentType::Plain, Stream::from(log))),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Err(Failure(Status::NotFound)),
+        Err(_) => Err(Failure(Status::InternalServerError)),
+    }
+}
+
+#[get("/v1/jobs/<project_name>/<job_id>/log/records")]
+fn get_job_log_records(
+    token: ValidToken,
+    config: State<Config>,
+    project_name: String,
+    job_id: JobId,
+) -> Result<Json<Vec<LogRecord>>, Failure> {
+    authorize(&token, &config, &project_name, Permission::ReadLogs)?;
+
+    match read_job_log(&project_name, job_id) {
+        Ok(records) => Ok(Json(records)),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Err(Failure(Status::NotFound)),
         Err(_) => Err(Failure(Status::InternalServerError)),
     }
 }
 
-pub(crate) fn start_server(config: Config, sender: WorkerSender) {
+#[get("/v1/jobs/<project_name>/<job_id>/artifacts/<name..>")]
+fn get_job_artifact(
+    token: ValidToken,
+    config: State<Config>,
+    project_name: String,
+    job_id: JobId,
+    name: PathBuf,
+) -> Result<NamedFile, Failure> {
+    authorize(&token, &config, &project_name, Permission::ReadArtifacts)?;
+
+    let path = job_artifacts_path(&project_name, job_id).join(name);
+
+    if !path.is_file() {
+        return Err(Failure(Status::NotFound));
+    }
+
+    NamedFile::open(path).map_err(|_| Failure(Status::InternalServerError))
+}
+
+#[delete("/v1/jobs/<project_name>/<job_id>")]
+fn cancel_job(
+    token: ValidToken,
+    config: State<Config>,
+    queue: State<Arc<JobQueue>>,
+    state: State<Arc<WorkerState>>,
+    project_name: String,
+    job_id: JobId,
+) -> Result<(), Failure> {
+    authorize(&token, &config, &project_name, Permission::Cancel)?;
+
+    if !is_known_job(&project_name, job_id)? {
+        return Err(Failure(Status::NotFound));
+    }
+
+    if is_pruned_job(&project_name, job_id)? {
+        return Err(Failure(Status::Gone));
+    }
+
+    match cancel_job(&queue, &state, &project_name, job_id) {
+        Ok(()) => Ok(()),
+        Err(CancelError::NotQueued) => Err(Failure(Status::Conflict)),
+        Err(CancelError::Archive(_)) => Err(Failure(Status::InternalServerError)),
+    }
+}
+
+///
+/// Approves or rejects a job that is awaiting approval.
+///
+fn decide_job(
+    token: &ValidToken,
+    config: &Config,
+    state: &WorkerState,
+    project_name: &str,
+    job_id: JobId,
+    approved: bool,
+) -> Result<(), Failure> {
+    authorize(token, config, project_name, Permission::Approve)?;
+
+    if !is_known_job(project_name, job_id)? {
+        return Err(Failure(Status::NotFound));
+    }
+
+    match state.decide(project_name, job_id, approved, token.token_name()) {
+        Ok(()) => Ok(()),
+        Err(ApprovalError::NotAwaitingApproval) => Err(Failure(Status::Conflict)),
+    }
+}
+
+#[post("/v1/jobs/<project_name>/<job_id>/approve")]
+fn approve_job(
+    token: ValidToken,
+    config: State<Config>,
+    state: State<Arc<WorkerState>>,
+    project_name: String,
+    job_id: JobId,
+) -> Result<(), Failure> {
+    decide_job(&token, &config, &state, &project_name, job_id, true)
+}
+
+#[post("/v1/jobs/<project_name>/<job_id>/reject")]
+fn reject_job(
+    token: ValidToken,
+    config: State<Config>,
+    state: State<Arc<WorkerState>>,
+    project_name: String,
+    job_id: JobId,
+) -> Result<(), Failure> {
+    decide_job(&token, &config, &state, &project_name, job_id, false)
+}
+
+pub(crate) fn start_server(config: Config, queue: Arc<JobQueue>, state: Arc<WorkerState>) {
     #[cfg(not(debug_assertions))]
     let environment = Environment::Production;
 
@@ -94,8 +388,22 @@ pub(crate) fn start_server(config: Config, sender: WorkerSender) {
                 config.port
             );
         }))
-        .manage(sender)
+        .manage(queue)
         .manage(config)
-        .mount("/", routes![create_job])
+        .manage(state)
+        .mount(
+            "/",
+            routes![
+                create_job,
+                get_job,
+                get_job_log,
+                get_job_log_records,
+                get_job_artifact,
+                get_queue,
+                approve_job,
+                reject_job,
+                cancel_job
+            ],
+        )
         .launch();
 }
@@ -1,4 +1,5 @@
 use crate::config::{self, Config};
+use crate::time::now;
 use rocket::Outcome;
 use rocket::http::Status;
 use rocket::request::{self, FromRequest, Request, State};
@@ -64,9 +65,24 @@ impl<'a, 'r> FromRequest<'a, 'r> for ValidToken<'a, 'r> {
             None => return forbidden!(),
         };
 
-        match config.tokens.get(token_str) {
-            Some(token) if token.secret == secret => Outcome::Success(ValidToken(token, token_str)),
-            _ => forbidden!(),
+        let token = match config.tokens.get(token_str) {
+            Some(token) if token.verify_secret(secret) => token,
+            _ => return forbidden!(),
+        };
+
+        if token.is_expired(now()) {
+            return forbidden!();
+        }
+
+        let allowed = match request.remote() {
+            Some(remote) => token.allows_address(remote.ip()),
+            None => token.allow_from.is_empty(),
+        };
+
+        if allowed {
+            Outcome::Success(ValidToken(token, token_str))
+        } else {
+            forbidden!()
         }
     }
 }
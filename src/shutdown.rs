@@ -0,0 +1,77 @@
+use crate::config::Config;
+use crate::status;
+use crate::time::format_duration;
+use crate::worker::WorkerState;
+use libc;
+use std::mem;
+use std::process;
+use std::ptr;
+
+fn shutdown_signals() -> libc::sigset_t {
+    unsafe {
+        let mut signals = mem::zeroed();
+
+        libc::sigemptyset(&mut signals);
+        libc::sigaddset(&mut signals, libc::SIGTERM);
+        libc::sigaddset(&mut signals, libc::SIGINT);
+
+        signals
+    }
+}
+
+fn signal_name(signal: libc::c_int) -> &'static str {
+    match signal {
+        libc::SIGTERM => "SIGTERM",
+        libc::SIGINT => "SIGINT",
+        _ => "signal",
+    }
+}
+
+///
+/// Blocks SIGTERM and SIGINT, so that they are only received by `handle_shutdown`.
+/// Must be called before any other thread is spawned, as threads inherit the blocked signals.
+///
+/// Commands run by jobs start with no signals blocked.
+///
+pub(crate) fn block_shutdown_signals() {
+    let signals = shutdown_signals();
+
+    unsafe {
+        libc::pthread_sigmask(libc::SIG_BLOCK, &signals, ptr::null_mut());
+    }
+}
+
+///
+/// Waits for SIGTERM or SIGINT and shuts tobyd down. Never returns.
+///
+/// New jobs are rejected and the worker stops after the running job. If the job doesn't
+/// finish within the grace period, it is interrupted. The remaining queue is saved by the worker.
+///
+pub(crate) fn handle_shutdown(config: &Config, state: &WorkerState) {
+    let signals = shutdown_signals();
+    let mut signal = 0;
+
+    unsafe {
+        libc::sigwait(&signals, &mut signal);
+    }
+
+    status!("Received {}, shutting down", signal_name(signal));
+
+    state.stop();
+
+    let grace_period = config.main.shutdown.grace_period();
+
+    if !state.await_worker_stopped(Some(grace_period)) {
+        status!(
+            "Job did not finish within {}, interrupting it",
+            format_duration(grace_period)
+        );
+
+        state.interrupt();
+        state.await_worker_stopped(None);
+    }
+
+    status!("Stopped");
+
+    process::exit(0);
+}
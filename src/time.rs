@@ -1,4 +1,4 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 pub(crate) fn now() -> u64 {
     let sys_time = SystemTime::now();
@@ -8,3 +8,276 @@ pub(crate) fn now() -> u64 {
         .expect("time went backwards")
         .as_secs()
 }
+
+///
+/// Returns the number of days since the unix epoch for a date of the proleptic gregorian calendar.
+///
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = (if year >= 0 { year } else { year - 399 }) / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * i64::from((month + 9) % 12) + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146_097 + day_of_era - 719_468
+}
+
+///
+/// Returns the (year, month, day) of the proleptic gregorian calendar for a number of days since the unix epoch.
+///
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = (if days >= 0 { days } else { days - 146_096 }) / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400;
+
+    (if month <= 2 { year + 1 } else { year }, month as u32, day as u32)
+}
+
+///
+/// The calendar fields of a point in time (UTC) that schedules are matched against.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct CalendarTime {
+    pub minute: u32,
+    pub hour: u32,
+    pub day: u32,
+    pub month: u32,
+    /// The day of the week, starting with 0 for Sunday
+    pub weekday: u32,
+}
+
+impl CalendarTime {
+    pub(crate) fn from_timestamp(timestamp: u64) -> Self {
+        let days = (timestamp / 86_400) as i64;
+        let seconds_of_day = timestamp % 86_400;
+        let (_, month, day) = civil_from_days(days);
+
+        CalendarTime {
+            minute: (seconds_of_day % 3600 / 60) as u32,
+            hour: (seconds_of_day / 3600) as u32,
+            day,
+            month,
+            // the unix epoch was a thursday
+            weekday: ((days + 4) % 7) as u32,
+        }
+    }
+}
+
+///
+/// Formats a point in time as an RFC 3339 date-time in UTC with millisecond precision.
+///
+pub(crate) fn format_timestamp(time: SystemTime) -> String {
+    let duration = time
+        .duration_since(UNIX_EPOCH)
+        .expect("time went backwards");
+    let seconds = duration.as_secs();
+    let (year, month, day) = civil_from_days((seconds / 86_400) as i64);
+    let seconds_of_day = seconds % 86_400;
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        seconds_of_day / 3600,
+        seconds_of_day % 3600 / 60,
+        seconds_of_day % 60,
+        duration.subsec_millis()
+    )
+}
+
+///
+/// Formats a duration in seconds with millisecond precision (e.g. `17.950s`).
+///
+pub(crate) fn format_duration(duration: Duration) -> String {
+    format!("{}.{:03}s", duration.as_secs(), duration.subsec_millis())
+}
+
+fn parse_number(input: &str, digits: usize) -> Option<u32> {
+    if input.len() != digits || !input.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+
+    input.parse().ok()
+}
+
+fn parse_offset(input: &str) -> Option<i64> {
+    if input.is_empty() || input == "Z" || input == "z" {
+        return Some(0);
+    }
+
+    let sign = match &input[..1] {
+        "+" => 1,
+        "-" => -1,
+        _ => return None,
+    };
+
+    let mut parts = input[1..].split(':');
+    let hours = parse_number(parts.next()?, 2)?;
+    let minutes = parse_number(parts.next()?, 2)?;
+
+    Some(sign * (i64::from(hours) * 3600 + i64::from(minutes) * 60))
+}
+
+///
+/// Parses an RFC 3339 date or date-time (as used by TOML) into a unix timestamp.
+/// Date-times without an offset are interpreted as UTC.
+///
+pub(crate) fn parse_rfc3339(input: &str) -> Option<u64> {
+    let (date, time) = match input.find(|c| c == 'T' || c == 't' || c == ' ') {
+        Some(pos) => (&input[..pos], &input[pos + 1..]),
+        None => (input, ""),
+    };
+
+    let mut date_parts = date.split('-');
+    let year = parse_number(date_parts.next()?, 4)?;
+    let month = parse_number(date_parts.next()?, 2)?;
+    let day = parse_number(date_parts.next()?, 2)?;
+
+    if date_parts.next().is_some() || month < 1 || month > 12 || day < 1 || day > 31 {
+        return None;
+    }
+
+    let mut seconds = days_from_civil(i64::from(year), month, day) * 86_400;
+
+    if !time.is_empty() {
+        if time.len() < 8 {
+            return None;
+        }
+
+        let hours = parse_number(&time[0..2], 2)?;
+        let minutes = parse_number(&time[3..5], 2)?;
+        let secs = parse_number(&time[6..8], 2)?;
+
+        if &time[2..3] != ":" || &time[5..6] != ":" || hours > 23 || minutes > 59 || secs > 60 {
+            return None;
+        }
+
+        let mut rest = &time[8..];
+
+        // fractional seconds are ignored
+        if rest.starts_with('.') {
+            let end = rest[1..]
+                .find(|c: char| !c.is_ascii_digit())
+                .map(|pos| pos + 1)
+                .unwrap_or_else(|| rest.len());
+
+            rest = &rest[end..];
+        }
+
+        seconds += i64::from(hours) * 3600 + i64::from(minutes) * 60 + i64::from(secs);
+        seconds -= parse_offset(rest)?;
+    }
+
+    if seconds < 0 {
+        None
+    } else {
+        Some(seconds as u64)
+    }
+}
+
+pub(crate) fn as_millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + u64::from(duration.subsec_millis())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timestamp(year: i64, month: u32, day: u32, hour: u64, minute: u64) -> u64 {
+        days_from_civil(year, month, day) as u64 * 86_400 + hour * 3600 + minute * 60
+    }
+
+    fn month_day(timestamp: u64) -> (u32, u32) {
+        let time = CalendarTime::from_timestamp(timestamp);
+
+        (time.day, time.month)
+    }
+
+    #[test]
+    fn converts_timestamps_to_calendar_time() {
+        let time = CalendarTime::from_timestamp(timestamp(2018, 7, 14, 13, 37));
+
+        assert_eq!(37, time.minute);
+        assert_eq!(13, time.hour);
+        assert_eq!(14, time.day);
+        assert_eq!(7, time.month);
+        assert_eq!(6, time.weekday);
+    }
+
+    #[test]
+    fn rolls_over_at_the_end_of_a_month() {
+        let last_minute = timestamp(2018, 4, 30, 23, 59);
+
+        assert_eq!((30, 4), month_day(last_minute));
+        assert_eq!((1, 5), month_day(last_minute + 60));
+    }
+
+    #[test]
+    fn rolls_over_at_the_end_of_february() {
+        assert_eq!((1, 3), month_day(timestamp(2018, 2, 28, 23, 59) + 60));
+        assert_eq!((29, 2), month_day(timestamp(2020, 2, 28, 23, 59) + 60));
+        assert_eq!((1, 3), month_day(timestamp(2020, 2, 29, 23, 59) + 60));
+    }
+
+    #[test]
+    fn rolls_over_at_the_end_of_a_year() {
+        let time = CalendarTime::from_timestamp(timestamp(2018, 12, 31, 23, 59) + 60);
+
+        assert_eq!((0, 0, 1, 1), (time.minute, time.hour, time.day, time.month));
+        assert_eq!(2, time.weekday);
+    }
+
+    #[test]
+    fn parses_rfc3339_date_times() {
+        let expected = Some(1_531_575_420);
+
+        assert_eq!(expected, parse_rfc3339("2018-07-14T13:37:00Z"));
+        assert_eq!(expected, parse_rfc3339("2018-07-14t13:37:00z"));
+        assert_eq!(expected, parse_rfc3339("2018-07-14 13:37:00"));
+        assert_eq!(expected, parse_rfc3339("2018-07-14T13:37:00.123456Z"));
+        assert_eq!(expected, parse_rfc3339("2018-07-14T15:37:00+02:00"));
+        assert_eq!(expected, parse_rfc3339("2018-07-14T11:37:00-02:00"));
+        assert_eq!(Some(0), parse_rfc3339("1970-01-01T00:00:00Z"));
+    }
+
+    #[test]
+    fn parses_rfc3339_dates_as_midnight() {
+        assert_eq!(Some(1_531_526_400), parse_rfc3339("2018-07-14"));
+        assert_eq!(Some(1_582_934_400), parse_rfc3339("2020-02-29"));
+    }
+
+    #[test]
+    fn rejects_invalid_rfc3339() {
+        for input in &[
+            "",
+            "2018-13-01",
+            "2018-00-10",
+            "2018-07-32",
+            "2018-7-14",
+            "18-07-14",
+            "2018-07-14-01",
+            "2018-07-14T25:00:00Z",
+            "2018-07-14T13:60:00Z",
+            "2018-07-14T13:37Z",
+            "2018-07-14T13-37-00Z",
+            "2018-07-14T13:37:00+0200",
+            "2018-07-14T13:37:00X",
+            "1969-12-31T23:59:59Z",
+            "1970-01-01T00:30:00+01:00",
+        ] {
+            assert_eq!(None, parse_rfc3339(input), "{} should be invalid", input);
+        }
+    }
+}
@@ -0,0 +1,196 @@
+use crate::config::Project;
+use libc::{self, gid_t, uid_t};
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::ptr;
+
+/// Size of the buffer for the strings of passwd and group entries
+const BUFFER_SIZE: usize = 16 * 1024;
+
+#[derive(Debug, Clone)]
+pub(crate) struct User {
+    pub(crate) name: String,
+    pub(crate) uid: uid_t,
+    pub(crate) gid: gid_t,
+    pub(crate) home: PathBuf,
+}
+
+///
+/// The user and groups the commands of a project are run as.
+///
+#[derive(Debug, Clone)]
+pub(crate) struct Credentials {
+    pub(crate) user: User,
+    pub(crate) gid: gid_t,
+    pub(crate) groups: Vec<gid_t>,
+}
+
+fn c_string(name: &str) -> io::Result<CString> {
+    CString::new(name).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "nul byte in name"))
+}
+
+fn not_found(kind: &str, name: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} {} does not exist", kind, name),
+    )
+}
+
+fn lookup_user(name: &str) -> io::Result<Option<User>> {
+    let c_name = c_string(name)?;
+    let mut passwd: libc::passwd = unsafe { mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; BUFFER_SIZE];
+    let mut result = ptr::null_mut();
+
+    let code = unsafe {
+        libc::getpwnam_r(
+            c_name.as_ptr(),
+            &mut passwd,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+
+    if code != 0 {
+        return Err(io::Error::from_raw_os_error(code));
+    }
+
+    if result.is_null() {
+        return Ok(None);
+    }
+
+    let home = unsafe { CStr::from_ptr(passwd.pw_dir) };
+
+    Ok(Some(User {
+        name: name.into(),
+        uid: passwd.pw_uid,
+        gid: passwd.pw_gid,
+        home: home.to_string_lossy().into_owned().into(),
+    }))
+}
+
+fn lookup_group(name: &str) -> io::Result<Option<gid_t>> {
+    let c_name = c_string(name)?;
+    let mut group: libc::group = unsafe { mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; BUFFER_SIZE];
+    let mut result = ptr::null_mut();
+
+    let code = unsafe {
+        libc::getgrnam_r(
+            c_name.as_ptr(),
+            &mut group,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+
+    if code != 0 {
+        return Err(io::Error::from_raw_os_error(code));
+    }
+
+    if result.is_null() {
+        Ok(None)
+    } else {
+        Ok(Some(group.gr_gid))
+    }
+}
+
+fn require_group(name: &str) -> io::Result<gid_t> {
+    lookup_group(name)?.ok_or_else(|| not_found("Group", name))
+}
+
+impl Credentials {
+    ///
+    /// Resolves the `user`, `group` and `groups` of a project.
+    /// Returns `None` if the project doesn't configure a user.
+    ///
+    pub(crate) fn for_project(project: &Project) -> io::Result<Option<Self>> {
+        let name = match project.user {
+            Some(ref name) => name,
+            None => return Ok(None),
+        };
+
+        let user = lookup_user(name)?.ok_or_else(|| not_found("User", name))?;
+
+        let gid = match project.group {
+            Some(ref group) => require_group(group)?,
+            None => user.gid,
+        };
+
+        let mut groups = vec![gid];
+
+        for group in &project.groups {
+            groups.push(require_group(group)?);
+        }
+
+        Ok(Some(Credentials { user, gid, groups }))
+    }
+
+    ///
+    /// Makes the command drop its privileges to the user and groups before it is executed.
+    ///
+    pub(crate) fn apply(&self, command: &mut Command) {
+        let uid = self.user.uid;
+        let gid = self.gid;
+        let groups = self.groups.clone();
+
+        command.before_exec(move || unsafe {
+            // nothing to drop if we already run as the user (e.g. `toby run` invoked by the user)
+            if libc::geteuid() == uid {
+                return Ok(());
+            }
+
+            if libc::setgroups(groups.len() as _, groups.as_ptr()) != 0
+                || libc::setgid(gid) != 0
+                || libc::setuid(uid) != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(())
+        });
+    }
+
+    ///
+    /// Hands a directory and everything in it over to the user, so that the commands can write
+    /// to it (including files left behind by jobs that ran as another user).
+    ///
+    pub(crate) fn chown(&self, path: &Path) -> io::Result<()> {
+        if unsafe { libc::geteuid() } == self.user.uid {
+            return Ok(());
+        }
+
+        self.chown_recursive(path)
+    }
+
+    fn chown_recursive(&self, path: &Path) -> io::Result<()> {
+        // symbolic links are changed themselves instead of being followed
+        let metadata = fs::symlink_metadata(path)?;
+
+        if metadata.uid() != self.user.uid || metadata.gid() != self.gid {
+            let c_path = CString::new(path.as_os_str().as_bytes())
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "nul byte in path"))?;
+
+            if unsafe { libc::lchown(c_path.as_ptr(), self.user.uid, self.gid) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        if metadata.is_dir() {
+            for entry in fs::read_dir(path)? {
+                self.chown_recursive(&entry?.path())?;
+            }
+        }
+
+        Ok(())
+    }
+}
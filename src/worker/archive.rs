@@ -0,0 +1,437 @@
+use super::context::CommandError;
+use super::model::{Approval, Job, JobId, JobTrigger, Parameters, Phase};
+use super::{Error, JobResult};
+use crate::config::Script;
+use crate::fs::{get_job_archive_file, open_job_archive};
+use crate::time::{as_millis, now};
+use std::io::{self, Read, Write};
+use std::time::Duration;
+use toml;
+
+///
+/// The version of the archive format written by this version of toby.
+/// Archives without a version field are treated as version 1.
+///
+const ARCHIVE_VERSION: u32 = 2;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ArchivedJob {
+    pub version: u32,
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+    pub duration_ms: Option<u64>,
+    pub successful: bool,
+    #[serde(default)]
+    pub cancelled: bool,
+    #[serde(default)]
+    pub interrupted: bool,
+    #[serde(default, rename = "ref")]
+    pub git_ref: Option<String>,
+    #[serde(default)]
+    pub commit: Option<String>,
+    pub trigger: JobTrigger,
+    #[serde(default)]
+    pub approval: Option<Approval>,
+    #[serde(default)]
+    pub parameters: Parameters,
+    #[serde(default)]
+    pub phases: Vec<ArchivedPhase>,
+    #[serde(default)]
+    pub steps: Vec<ArchivedStep>,
+    #[serde(default)]
+    pub artifacts: Vec<ArchivedArtifact>,
+}
+
+///
+/// Outcome of the scripts that run after the main scripts
+/// (`on_success`, `on_failure` and `finally`).
+///
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ArchivedPhase {
+    pub phase: Phase,
+    pub successful: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ArchivedStep {
+    pub name: Option<String>,
+    pub command: Vec<String>,
+    #[serde(default)]
+    pub run: Option<String>,
+    pub phase: Phase,
+    pub started_at: u64,
+    pub finished_at: u64,
+    pub duration_ms: u64,
+    pub successful: bool,
+    pub allow_failure: bool,
+    #[serde(default = "default_attempts")]
+    pub attempts: u32,
+    #[serde(default)]
+    pub skipped: bool,
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    pub error: Option<String>,
+}
+
+///
+/// A file that has been collected from the job's workspace.
+///
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ArchivedArtifact {
+    /// The path of the file relative to the workspace
+    pub name: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct ArchivedJobV1 {
+    started_at: u64,
+    successful: bool,
+    #[serde(default)]
+    cancelled: bool,
+    trigger: JobTrigger,
+    #[serde(default)]
+    phases: Vec<ArchivedPhase>,
+}
+
+#[derive(Deserialize)]
+struct ArchiveVersion {
+    #[serde(default = "default_version")]
+    version: u32,
+}
+
+///
+/// Collects the outcome of phases and steps while a job is running.
+///
+#[derive(Debug, Default)]
+pub(crate) struct JobReport {
+    pub approval: Option<Approval>,
+    pub git_ref: Option<String>,
+    pub commit: Option<String>,
+    pub phases: Vec<ArchivedPhase>,
+    pub steps: Vec<ArchivedStep>,
+    pub artifacts: Vec<ArchivedArtifact>,
+    pub interrupted: bool,
+}
+
+fn default_version() -> u32 {
+    1
+}
+
+fn default_attempts() -> u32 {
+    1
+}
+
+impl From<ArchivedJobV1> for ArchivedJob {
+    fn from(archive: ArchivedJobV1) -> Self {
+        ArchivedJob {
+            version: ARCHIVE_VERSION,
+            started_at: archive.started_at,
+            finished_at: None,
+            duration_ms: None,
+            successful: archive.successful,
+            cancelled: archive.cancelled,
+            interrupted: false,
+            git_ref: None,
+            commit: None,
+            trigger: archive.trigger,
+            approval: None,
+            parameters: Parameters::new(),
+            phases: archive.phases,
+            steps: Vec::new(),
+            artifacts: Vec::new(),
+        }
+    }
+}
+
+impl ArchivedJob {
+    ///
+    /// Whether the job has been rejected (or its approval timed out) instead of running.
+    ///
+    pub(crate) fn is_rejected(&self) -> bool {
+        self.approval
+            .as_ref()
+            .map_or(false, |approval| !approval.approved)
+    }
+}
+
+impl ArchivedStep {
+    pub(crate) fn new(
+        phase: Phase,
+        script: &Script,
+        started_at: u64,
+        duration: Duration,
+        result: &Result<(), CommandError>,
+        attempts: u32,
+    ) -> Self {
+        let (exit_code, signal, error) = match *result {
+            Ok(()) => (Some(0), None, None),
+            Err(ref err) => (err.exit_code(), err.signal(), Some(err.to_string())),
+        };
+
+        ArchivedStep {
+            name: script.name.clone(),
+            command: script.command.clone(),
+            run: script.run.clone(),
+            phase,
+            started_at,
+            finished_at: now(),
+            duration_ms: as_millis(duration),
+            successful: result.is_ok(),
+            allow_failure: script.allow_failure,
+            attempts,
+            skipped: false,
+            exit_code,
+            signal,
+            error,
+        }
+    }
+
+    ///
+    /// A step whose `when` condition wasn't met.
+    ///
+    pub(crate) fn skipped(phase: Phase, script: &Script) -> Self {
+        let skipped_at = now();
+
+        ArchivedStep {
+            name: script.name.clone(),
+            command: script.command.clone(),
+            run: script.run.clone(),
+            phase,
+            started_at: skipped_at,
+            finished_at: skipped_at,
+            duration_ms: 0,
+            successful: true,
+            allow_failure: script.allow_failure,
+            attempts: 0,
+            skipped: true,
+            exit_code: None,
+            signal: None,
+            error: None,
+        }
+    }
+}
+
+impl Job {
+    pub(crate) fn archive(
+        &self,
+        started_at: u64,
+        duration: Duration,
+        successful: bool,
+        report: JobReport,
+    ) -> ArchivedJob {
+        ArchivedJob {
+            version: ARCHIVE_VERSION,
+            trigger: self.trigger.clone(),
+            approval: report.approval,
+            parameters: self.parameters.clone(),
+            started_at,
+            finished_at: Some(now()),
+            duration_ms: Some(as_millis(duration)),
+            successful,
+            cancelled: false,
+            interrupted: report.interrupted,
+            git_ref: report.git_ref,
+            commit: report.commit,
+            phases: report.phases,
+            steps: report.steps,
+            artifacts: report.artifacts,
+        }
+    }
+
+    ///
+    /// The archive of a job that has been rejected (or whose approval timed out).
+    ///
+    pub(crate) fn archive_rejected(&self, approval: Approval) -> ArchivedJob {
+        let report = JobReport {
+            approval: Some(approval),
+            ..Default::default()
+        };
+
+        self.archive(now(), Duration::from_secs(0), false, report)
+    }
+
+    pub(crate) fn archive_cancelled(&self, cancelled_at: u64) -> ArchivedJob {
+        ArchivedJob {
+            cancelled: true,
+            ..self.archive(
+                cancelled_at,
+                Duration::from_secs(0),
+                false,
+                Default::default(),
+            )
+        }
+    }
+}
+
+///
+/// Serializes an archive. Going through `toml::Value` puts plain values before tables,
+/// which TOML requires no matter in which order the fields are declared.
+///
+fn serialize_archive(archived_job: &ArchivedJob) -> Result<String, toml::ser::Error> {
+    toml::Value::try_from(archived_job).and_then(|value| toml::to_string(&value))
+}
+
+pub(crate) fn write_archive(job: &Job, archived_job: &ArchivedJob) -> JobResult {
+    let archived_job_str = serialize_archive(archived_job)
+        .map_err(|err| Error::Archive(io::Error::new(io::ErrorKind::InvalidData, err)))?;
+    let file = get_job_archive_file(&job.project, job.id).map_err(Error::Archive)?;
+    let mut buf_writer = io::BufWriter::new(file);
+
+    buf_writer
+        .write_all(archived_job_str.as_bytes())
+        .map_err(Error::Archive)?;
+
+    Ok(())
+}
+
+fn parse_archive(contents: &str) -> Result<ArchivedJob, String> {
+    let version: ArchiveVersion = toml::from_str(contents).map_err(|err| err.to_string())?;
+
+    match version.version {
+        1 => toml::from_str::<ArchivedJobV1>(contents)
+            .map(ArchivedJob::from)
+            .map_err(|err| err.to_string()),
+        ARCHIVE_VERSION => toml::from_str(contents).map_err(|err| err.to_string()),
+        version => Err(format!("unsupported archive version {}", version)),
+    }
+}
+
+///
+/// Reads the archive of a job. Returns `None` if the job has not been archived (yet).
+/// Archives written by older versions of toby are upgraded to the current format.
+///
+pub(crate) fn read_job_archive(
+    project_name: &str,
+    job_id: JobId,
+) -> io::Result<Option<ArchivedJob>> {
+    let mut file = match open_job_archive(project_name, job_id)? {
+        Some(file) => file,
+        None => return Ok(None),
+    };
+
+    let mut contents = String::new();
+
+    file.read_to_string(&mut contents)?;
+
+    parse_archive(&contents)
+        .map(Some)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn archived_job(report: JobReport) -> ArchivedJob {
+        let job = Job {
+            id: 1,
+            project: "dreams".into(),
+            priority: 0,
+            trigger: JobTrigger::Cli {
+                user: "root".into(),
+            },
+            revision: Default::default(),
+            parameters: vec![("env".to_string(), "staging".to_string())]
+                .into_iter()
+                .collect(),
+        };
+
+        job.archive(1000, Duration::from_secs(2), true, report)
+    }
+
+    fn archived_step(phase: Phase) -> ArchivedStep {
+        ArchivedStep {
+            name: Some("build".into()),
+            command: vec!["make".into(), "all".into()],
+            run: None,
+            phase,
+            started_at: 1000,
+            finished_at: 2000,
+            duration_ms: 1000,
+            successful: true,
+            allow_failure: false,
+            attempts: 1,
+            skipped: false,
+            exit_code: Some(0),
+            signal: None,
+            error: None,
+        }
+    }
+
+    fn round_trip(archived_job: &ArchivedJob) -> ArchivedJob {
+        let contents = serialize_archive(archived_job).expect("unable to serialize archive");
+
+        parse_archive(&contents).expect("unable to parse archive")
+    }
+
+    #[test]
+    fn round_trips_empty_archive() {
+        let archive = round_trip(&archived_job(Default::default()));
+
+        assert_eq!(ARCHIVE_VERSION, archive.version);
+        assert!(archive.successful);
+        assert_eq!(
+            Some("staging"),
+            archive.parameters.get("env").map(String::as_str)
+        );
+        assert!(archive.phases.is_empty());
+        assert!(archive.steps.is_empty());
+        assert!(archive.artifacts.is_empty());
+    }
+
+    #[test]
+    fn round_trips_archive_with_steps_and_artifacts() {
+        let report = JobReport {
+            approval: Some(Approval {
+                approved: true,
+                by: Some("travis".into()),
+                decided_at: 500,
+            }),
+            commit: Some("abcdef".into()),
+            phases: vec![ArchivedPhase {
+                phase: Phase::Finally,
+                successful: true,
+            }],
+            steps: vec![archived_step(Phase::Scripts), archived_step(Phase::Finally)],
+            artifacts: vec![ArchivedArtifact {
+                name: "target/app.tar.gz".into(),
+                size: 42,
+            }],
+            ..Default::default()
+        };
+
+        let archive = round_trip(&archived_job(report));
+
+        assert_eq!(Some("abcdef"), archive.commit.as_ref().map(String::as_str));
+        assert_eq!(
+            Some(true),
+            archive.approval.map(|approval| approval.approved)
+        );
+        assert_eq!(1, archive.phases.len());
+        assert_eq!(2, archive.steps.len());
+        assert_eq!(vec!["make", "all"], archive.steps[0].command);
+        assert_eq!(Phase::Finally, archive.steps[1].phase);
+        assert_eq!("target/app.tar.gz", archive.artifacts[0].name);
+        assert_eq!(42, archive.artifacts[0].size);
+    }
+
+    #[test]
+    fn round_trips_cancelled_archive() {
+        let job = Job {
+            id: 2,
+            project: "dreams".into(),
+            priority: 0,
+            trigger: JobTrigger::Schedule {
+                schedule: "0 3 * * *".into(),
+            },
+            revision: Default::default(),
+            parameters: Parameters::new(),
+        };
+
+        let archive = round_trip(&job.archive_cancelled(1000));
+
+        assert!(archive.cancelled);
+        assert!(!archive.successful);
+    }
+}
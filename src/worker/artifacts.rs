@@ -0,0 +1,64 @@
+use super::archive::ArchivedArtifact;
+use super::model::Job;
+use crate::fs::job_artifacts_path;
+use glob::{glob, Pattern};
+use std::fs::{self, DirBuilder};
+use std::io;
+use std::path::{Path, PathBuf};
+
+///
+/// Finds the files in the workspace that match an artifact pattern.
+/// Returns their paths relative to the workspace.
+///
+/// Files outside of the workspace (e.g. reached through a symlink created by a script)
+/// are skipped, as tobyd may be able to read files that the scripts can't.
+///
+pub(crate) fn find_artifacts(workspace: &Path, pattern: &str) -> io::Result<Vec<PathBuf>> {
+    let workspace = workspace.canonicalize()?;
+    let escaped = Pattern::escape(&workspace.to_string_lossy());
+    let paths = glob(&format!("{}/{}", escaped, pattern))
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?;
+
+    let mut artifacts = Vec::new();
+
+    // entries that can't be read are skipped
+    for path in paths.filter_map(Result::ok) {
+        // broken symlinks can't be resolved
+        let path = match path.canonicalize() {
+            Ok(path) => path,
+            Err(_) => continue,
+        };
+
+        if !path.is_file() {
+            continue;
+        }
+
+        if let Ok(relative) = path.strip_prefix(&workspace) {
+            artifacts.push(relative.to_path_buf());
+        }
+    }
+
+    Ok(artifacts)
+}
+
+///
+/// Copies an artifact from the workspace into the job's artifacts directory.
+///
+pub(crate) fn store_artifact(
+    job: &Job,
+    workspace: &Path,
+    name: &Path,
+) -> io::Result<ArchivedArtifact> {
+    let target = job_artifacts_path(&job.project, job.id).join(name);
+
+    if let Some(dir) = target.parent() {
+        DirBuilder::new().recursive(true).create(dir)?;
+    }
+
+    let size = fs::copy(workspace.join(name), &target)?;
+
+    Ok(ArchivedArtifact {
+        name: name.to_string_lossy().into_owned(),
+        size,
+    })
+}
@@ -1,31 +1,61 @@
-use crate::config::Project;
-use crate::fs::get_job_log;
-use crate::worker::Job;
-use std::borrow::{Borrow, Cow};
-use std::collections::HashMap;
+use super::environment::{base_environment, Environment};
+use super::limits::{apply_rlimits, Cgroup, Limit};
+use super::log::{forward_lines, JobOutput, Stream};
+use super::state::WorkerState;
+use super::workspace::JobWorkspace;
+use crate::config::{find_script, LimitsConfig, Project, Script, Variables};
+use crate::user::Credentials;
+use crate::worker::{Job, Phase};
+use libc;
+use std::borrow::Borrow;
 use std::ffi::OsStr;
 use std::fmt;
-use std::fs::File;
+use std::fs;
 use std::io;
-use std::io::Write;
-use std::process::{Command, ExitStatus, Stdio};
-use std::slice::SliceConcatExt;
-use tempdir::TempDir;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
 
 const UNKNOWN_EXIT_STATUS: i32 = -1;
 
+/// How often to check whether the child has exited while waiting for output (in milliseconds)
+const POLL_INTERVAL_MS: u64 = 100;
+
+/// How long to wait for output of background processes after the child has exited (in milliseconds)
+const OUTPUT_GRACE_PERIOD_MS: u64 = 1000;
+
+/// Shells exit with 128 plus the signal number if their last command was killed by a signal
+const SIGNAL_EXIT_STATUS_OFFSET: i32 = 128;
+
 #[derive(Debug)]
 pub(crate) enum CommandError {
     ExitStatus(ExitStatus),
+    LimitExceeded(Limit, ExitStatus),
+    Interrupted,
     Io(io::Error),
 }
 
 #[derive(Debug)]
 pub(crate) struct JobContext<'a> {
-    current_dir: TempDir,
+    workspace: JobWorkspace,
+    credentials: Option<Credentials>,
+    limits: &'a LimitsConfig,
+    commands_run: u32,
+    failed: bool,
+    /// Set for jobs run by tobyd, which can be interrupted when it shuts down
+    state: Option<&'a WorkerState>,
     job: &'a Job,
-    environment: HashMap<&'a str, Cow<'a, str>>,
-    log_file: File,
+    environment: Environment,
+    output: JobOutput,
+}
+
+/// The variables toby sets for a job, which unlike the inherited environment are safe to log
+pub(crate) struct JobVariables<'a> {
+    environment: &'a Environment,
+    workspace: &'a Path,
 }
 
 impl From<io::Error> for CommandError {
@@ -34,6 +64,26 @@ impl From<io::Error> for CommandError {
     }
 }
 
+impl CommandError {
+    pub(crate) fn exit_code(&self) -> Option<i32> {
+        match *self {
+            CommandError::ExitStatus(ref status) | CommandError::LimitExceeded(_, ref status) => {
+                status.code()
+            }
+            CommandError::Interrupted | CommandError::Io(..) => None,
+        }
+    }
+
+    pub(crate) fn signal(&self) -> Option<i32> {
+        match *self {
+            CommandError::ExitStatus(ref status) | CommandError::LimitExceeded(_, ref status) => {
+                status.signal()
+            }
+            CommandError::Interrupted | CommandError::Io(..) => None,
+        }
+    }
+}
+
 impl fmt::Display for CommandError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
@@ -42,6 +92,10 @@ impl fmt::Display for CommandError {
                 "Command failed with exit status: {}",
                 status.code().unwrap_or(UNKNOWN_EXIT_STATUS)
             ),
+            CommandError::LimitExceeded(limit, _) => {
+                write!(f, "Command exceeded the {} limit", limit)
+            }
+            CommandError::Interrupted => write!(f, "Command was interrupted by shutdown"),
             CommandError::Io(ref err) => write!(f, "Command failed: {}", err),
         }
     }
@@ -53,66 +107,363 @@ impl<'a> fmt::Display for JobContext<'a> {
             writeln!(f, "  {}={}", key, value)?;
         }
 
-        write!(f, "  PWD={}", self.current_dir.path().to_string_lossy())?;
+        write!(f, "  PWD={}", self.workspace.path().to_string_lossy())?;
+
+        Ok(())
+    }
+}
+
+impl<'a> fmt::Display for JobVariables<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (key, value) in self.environment {
+            if key.starts_with("TOBY_") {
+                writeln!(f, "  {}={}", key, value)?;
+            }
+        }
+
+        write!(f, "  PWD={}", self.workspace.to_string_lossy())?;
 
         Ok(())
     }
 }
 
 impl<'a> JobContext<'a> {
-    pub(crate) fn new(job: &'a Job, project: &'a Project) -> io::Result<Self> {
-        let current_dir = TempDir::new("toby-job")?;
+    pub(crate) fn new(
+        job: &'a Job,
+        project: &'a Project,
+        workspace: JobWorkspace,
+        credentials: Option<Credentials>,
+        output: JobOutput,
+        state: Option<&'a WorkerState>,
+    ) -> Self {
+        let mut environment = base_environment(&project.inherit_environment);
+
+        if let Some(ref credentials) = credentials {
+            let user = &credentials.user;
 
-        let mut environment: HashMap<&'a str, Cow<'a, str>> = project
-            .environment
-            .iter()
-            .map(|(key, value)| (key.as_str(), Cow::Borrowed(value.as_str())))
-            .collect();
+            environment.insert("USER".into(), user.name.clone());
+            environment.insert("LOGNAME".into(), user.name.clone());
+            environment.insert("HOME".into(), user.home.to_string_lossy().into_owned());
+        }
+
+        environment.extend(project.environment.clone());
 
-        environment.insert("TOBY_JOB_ID", job.id.to_string().into());
-        environment.insert("TOBY_JOB_TRIGGER", job.trigger.name().into());
+        environment.insert("TOBY_JOB_ID".into(), job.id.to_string());
+        environment.insert("TOBY_JOB_TRIGGER".into(), job.trigger.name().into());
 
-        let log_file = get_job_log(&job.project, job.id)?;
+        for (name, value) in &job.parameters {
+            environment.insert(format!("TOBY_PARAM_{}", name), value.clone());
+        }
 
-        Ok(Self {
-            current_dir,
+        Self {
+            workspace,
+            credentials,
+            limits: &project.limits,
+            commands_run: 0,
+            failed: false,
+            state,
             job,
             environment,
-            log_file,
+            output,
+        }
+    }
+
+    ///
+    /// Empties the job's workspace if it is persistent.
+    ///
+    pub(crate) fn clean_workspace(&mut self) -> io::Result<()> {
+        self.log("Cleaning workspace")?;
+        self.workspace.clean()
+    }
+
+    ///
+    /// Exposes the failed step and its error to the scripts that handle the failure.
+    ///
+    pub(crate) fn set_failure(&mut self, step: &str, error: &str) {
+        self.environment
+            .insert("TOBY_FAILED_STEP".into(), step.into());
+        self.environment.insert("TOBY_ERROR".into(), error.into());
+    }
+
+    ///
+    /// Exposes the checked out ref and commit to the scripts.
+    ///
+    pub(crate) fn set_revision(&mut self, git_ref: Option<&str>, commit: &str) {
+        if let Some(git_ref) = git_ref {
+            self.environment.insert("TOBY_REF".into(), git_ref.into());
+        }
+
+        self.environment.insert("TOBY_COMMIT".into(), commit.into());
+    }
+
+    ///
+    /// Records that a step failed (even if its failure is allowed).
+    ///
+    pub(crate) fn mark_failed(&mut self) {
+        self.failed = true;
+    }
+
+    ///
+    /// Evaluates the script's `when` condition. Scripts without a condition always run.
+    ///
+    pub(crate) fn should_run(&self, script: &Script) -> bool {
+        let condition = match script.when {
+            Some(ref condition) => condition,
+            None => return true,
+        };
+
+        condition.evaluate(&Variables {
+            trigger: self.job.trigger.name(),
+            failed: self.failed,
+            environment: &self.environment,
+            parameters: &self.job.parameters,
         })
     }
 
-    pub(crate) fn log_file(&mut self) -> &mut File {
-        &mut self.log_file
+    ///
+    /// Whether tobyd has interrupted the job because it is shutting down.
+    ///
+    pub(crate) fn is_interrupted(&self) -> bool {
+        self.state.map_or(false, WorkerState::is_interrupted)
+    }
+
+    ///
+    /// Waits before retrying a script. Returns `false` if tobyd is shutting down,
+    /// in which case the script must not be retried.
+    ///
+    pub(crate) fn wait_before_retry(&self, delay: Duration) -> bool {
+        match self.state {
+            Some(state) => state.sleep(delay),
+            None => {
+                thread::sleep(delay);
+                true
+            }
+        }
+    }
+
+    pub(crate) fn workspace_path(&self) -> &Path {
+        self.workspace.path()
+    }
+
+    ///
+    /// Returns the variables toby sets for the job, leaving out the inherited environment.
+    ///
+    pub(crate) fn job_variables(&self) -> JobVariables {
+        JobVariables {
+            environment: &self.environment,
+            workspace: self.workspace.path(),
+        }
+    }
+
+    ///
+    /// Sets the step that subsequent lines of the job's log belong to.
+    ///
+    pub(crate) fn set_step(&mut self, step: Option<String>) {
+        self.output.set_step(step);
+    }
+
+    ///
+    /// Writes a message from toby to the job's log.
+    ///
+    pub(crate) fn log(&mut self, message: &str) -> io::Result<()> {
+        self.output.write_line(Stream::Toby, message)
+    }
+
+    ///
+    /// Runs a script, either its command or its `run` source using the script's shell.
+    ///
+    pub(crate) fn run_script(&mut self, phase: Phase, script: &Script) -> Result<(), CommandError> {
+        let source = match script.run {
+            Some(ref source) => source,
+            None => {
+                let mut command = script.command.clone();
+
+                // scripts in scripts.d can be referred to by their file name,
+                // but they must not replace the git commands of the checkout
+                if phase != Phase::Checkout {
+                    if let Some(path) = find_script(&command[0]) {
+                        command[0] = path.to_string_lossy().into_owned();
+                    }
+                }
+
+                return self.run_command(&command);
+            }
+        };
+
+        let file_name = format!(".toby-script-{}-{}", self.job.id, self.commands_run + 1);
+        let path = self.workspace.path().join(&file_name);
+
+        fs::write(&path, source)?;
+
+        let mut command = script.shell();
+
+        // the shell is started in the working directory, so the relative path suffices
+        command.push(file_name);
+
+        let result = self.run_command(&command);
+
+        if let Err(err) = fs::remove_file(&path) {
+            self.log(&format!("Unable to remove script file: {}", err))?;
+        }
+
+        result
     }
 
-    pub(crate) fn run_command<S>(&mut self, command: &[S]) -> Result<(), CommandError>
+    fn run_command<S>(&mut self, command: &[S]) -> Result<(), CommandError>
     where
         S: Borrow<str> + AsRef<OsStr>,
     {
-        writeln!(
-            self.log_file,
-            "[toby] Running command {}",
-            command.join(" ")
-        )?;
+        if self.is_interrupted() {
+            return Err(CommandError::Interrupted);
+        }
+
+        self.commands_run += 1;
 
         let mut cmd = Command::new(&command[0]);
 
         cmd.args(&command[1..])
-            .current_dir(&self.current_dir)
-            .stdout(Stdio::from(self.log_file.try_clone()?))
-            .stderr(Stdio::from(self.log_file.try_clone()?));
+            .current_dir(self.workspace.path())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .env_clear()
+            .envs(&self.environment);
 
-        for (key, value) in &self.environment {
-            cmd.env(key, value.as_ref());
+        let cgroup = self.create_cgroup()?;
+
+        // limits are applied before dropping privileges, as lowering them doesn't require any
+        apply_rlimits(&mut cmd, self.limits);
+
+        if let Some(ref cgroup) = cgroup {
+            cgroup.attach(&mut cmd)?;
+        }
+
+        if let Some(ref credentials) = self.credentials {
+            credentials.apply(&mut cmd);
         }
 
-        let status = cmd.status()?;
+        if self.state.is_some() {
+            // the command and its children can be interrupted together
+            cmd.before_exec(|| {
+                if unsafe { libc::setpgid(0, 0) } != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+
+                Ok(())
+            });
+        }
+
+        let mut child = cmd.spawn()?;
+        let (sender, receiver) = mpsc::channel();
+
+        if let Some(state) = self.state {
+            state.enter_process_group(child.id());
+        }
+
+        if let Some(stdout) = child.stdout.take() {
+            forward_lines(stdout, Stream::Stdout, sender.clone());
+        }
+
+        if let Some(stderr) = child.stderr.take() {
+            forward_lines(stderr, Stream::Stderr, sender);
+        }
+
+        let status = self.capture_output(&mut child, &receiver);
+
+        if let Some(state) = self.state {
+            state.leave_process_group();
+        }
+
+        let status = status?;
 
         if status.success() {
             Ok(())
+        } else if self.exceeded_cpu_time(status) {
+            Err(CommandError::LimitExceeded(Limit::CpuTime, status))
+        } else if cgroup.map_or(false, |cgroup| cgroup.oom_killed()) {
+            Err(CommandError::LimitExceeded(Limit::Memory, status))
         } else {
             Err(CommandError::ExitStatus(status))
         }
     }
+
+    ///
+    /// Whether the command was killed for exceeding the cpu time limit. Processes started by a
+    /// shell (e.g. for `run` scripts) are detected from the exit status the shell passes on.
+    ///
+    fn exceeded_cpu_time(&self, status: ExitStatus) -> bool {
+        status.signal() == Some(libc::SIGXCPU)
+            || (self.limits.cpu_time_secs.is_some()
+                && status.code() == Some(SIGNAL_EXIT_STATUS_OFFSET + libc::SIGXCPU))
+    }
+
+    ///
+    /// Creates a cgroup for the next command if the project has memory or cpu limits.
+    /// Those limits are not enforced if cgroups are not available.
+    ///
+    fn create_cgroup(&mut self) -> io::Result<Option<Cgroup>> {
+        if !self.limits.needs_cgroup() {
+            return Ok(None);
+        }
+
+        let name = format!("{}-{}-{}", self.job.project, self.job.id, self.commands_run);
+
+        match Cgroup::create(&name, self.limits) {
+            Ok(cgroup) => Ok(Some(cgroup)),
+            Err(err) => {
+                self.log(&format!(
+                    "Unable to create cgroup, memory and cpu limits are not enforced: {}",
+                    err
+                ))?;
+
+                Ok(None)
+            }
+        }
+    }
+
+    ///
+    /// Writes the child's output to the job's output until the child has exited and its pipes are closed.
+    /// Output of background processes that keep the pipes open is only captured for a short grace period
+    /// after the child has exited.
+    ///
+    fn capture_output(
+        &mut self,
+        child: &mut Child,
+        receiver: &Receiver<(Stream, String)>,
+    ) -> io::Result<ExitStatus> {
+        let mut status = None;
+        let mut write_result = Ok(());
+
+        loop {
+            let timeout = if status.is_some() {
+                OUTPUT_GRACE_PERIOD_MS
+            } else {
+                POLL_INTERVAL_MS
+            };
+
+            match receiver.recv_timeout(Duration::from_millis(timeout)) {
+                Ok((stream, line)) => {
+                    // keep draining the pipes after a failed write, so that the child doesn't block
+                    if write_result.is_ok() {
+                        write_result = self.output.write_line(stream, &line);
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+                Err(RecvTimeoutError::Timeout) if status.is_some() => break,
+                Err(RecvTimeoutError::Timeout) => {}
+            };
+
+            if status.is_none() {
+                status = child.try_wait()?;
+            }
+        }
+
+        let status = match status {
+            Some(status) => status,
+            None => child.wait()?,
+        };
+
+        write_result?;
+
+        Ok(status)
+    }
 }
@@ -0,0 +1,63 @@
+use crate::config::InheritEnvironment;
+use std::collections::BTreeMap;
+use std::env;
+
+pub(crate) type Environment = BTreeMap<String, String>;
+
+/// Variables that are always set when tobyd's environment isn't inherited, with their fallbacks
+const MINIMAL_ENVIRONMENT: &[(&str, &str)] = &[
+    (
+        "PATH",
+        "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin",
+    ),
+    ("HOME", "/"),
+    ("LANG", "C.UTF-8"),
+];
+
+fn inherited_variables() -> Environment {
+    // variables that aren't valid unicode are skipped
+    env::vars_os()
+        .filter_map(
+            |(key, value)| match (key.into_string(), value.into_string()) {
+                (Ok(key), Ok(value)) => Some((key, value)),
+                _ => None,
+            },
+        )
+        .collect()
+}
+
+fn minimal_environment(inherited: &Environment) -> Environment {
+    MINIMAL_ENVIRONMENT
+        .iter()
+        .map(|&(key, fallback)| {
+            let value = inherited
+                .get(key)
+                .map_or_else(|| fallback.to_string(), String::clone);
+
+            (key.to_string(), value)
+        })
+        .collect()
+}
+
+///
+/// Determines the variables of tobyd's environment that a job starts out with.
+///
+pub(crate) fn base_environment(policy: &InheritEnvironment) -> Environment {
+    let inherited = inherited_variables();
+
+    match *policy {
+        InheritEnvironment::All => inherited,
+        InheritEnvironment::None => minimal_environment(&inherited),
+        InheritEnvironment::Only(ref names) => {
+            let mut environment = minimal_environment(&inherited);
+
+            for name in names {
+                if let Some(value) = inherited.get(name) {
+                    environment.insert(name.clone(), value.clone());
+                }
+            }
+
+            environment
+        }
+    }
+}
@@ -1,10 +1,15 @@
 use super::JobResult;
+use super::log::{read_job_log, Stream};
 use super::model::Job;
 use crate::config::{Config, SendLog};
 use crate::fs::job_log_path;
 use crate::status;
 use crate::telegram;
 use reqwest;
+use std::slice::SliceConcatExt;
+
+/// Number of output lines included in failure notifications
+const FAILURE_OUTPUT_LINES: usize = 10;
 
 #[derive(Debug)]
 struct TelegramHook {
@@ -19,10 +24,32 @@ pub(crate) struct Hooks {
 }
 
 pub(crate) trait Hook {
+    fn awaiting_approval(&self, job: &Job);
     fn before_job(&self, job: &Job);
     fn after_job(&self, job: &Job, result: &JobResult);
 }
 
+///
+/// Returns the last lines of output (stdout and stderr) of a job.
+///
+fn last_output_lines(job: &Job, count: usize) -> Vec<String> {
+    let records = match read_job_log(&job.project, job.id) {
+        Ok(records) => records,
+        Err(..) => return Vec::new(),
+    };
+
+    let mut lines: Vec<_> = records
+        .into_iter()
+        .rev()
+        .filter(|record| record.stream != Stream::Toby)
+        .take(count)
+        .map(|record| record.text.replace("```", "'''"))
+        .collect();
+
+    lines.reverse();
+    lines
+}
+
 impl Hooks {
     pub(crate) fn from_config(config: &Config, telegram_chat_id: Option<i64>) -> Self {
         let telegram = TelegramHook::from_config(config, telegram_chat_id);
@@ -32,6 +59,12 @@ impl Hooks {
 }
 
 impl Hook for Hooks {
+    fn awaiting_approval(&self, job: &Job) {
+        if let Some(ref telegram) = self.telegram {
+            telegram.awaiting_approval(job);
+        }
+    }
+
     fn before_job(&self, job: &Job) {
         if let Some(ref telegram) = self.telegram {
             telegram.before_job(job);
@@ -59,16 +92,11 @@ impl TelegramHook {
     }
 }
 
-impl Hook for TelegramHook {
-    fn before_job(&self, job: &Job) {
-        let message = format!(
-            "⌛️ Job *#{}* for project *{}* triggered by {}...",
-            job.id, job.project, job.trigger
-        );
-
+impl TelegramHook {
+    fn send_message(&self, message: &str) {
         let result = self.api.send_message(&telegram::SendMessageParams {
             chat_id: &self.chat_id,
-            text: &message,
+            text: message,
             parse_mode: Some(telegram::ParseMode::Markdown),
             ..Default::default()
         });
@@ -77,6 +105,22 @@ impl Hook for TelegramHook {
             status!("Unable to send telegram message: {}", err);
         }
     }
+}
+
+impl Hook for TelegramHook {
+    fn awaiting_approval(&self, job: &Job) {
+        self.send_message(&format!(
+            "✋ Job *#{}* for project *{}* triggered by {} is awaiting approval.",
+            job.id, job.project, job.trigger
+        ));
+    }
+
+    fn before_job(&self, job: &Job) {
+        self.send_message(&format!(
+            "⌛️ Job *#{}* for project *{}* triggered by {}...",
+            job.id, job.project, job.trigger
+        ));
+    }
 
     fn after_job(&self, job: &Job, result: &JobResult) {
         let project_name = &job.project;
@@ -86,22 +130,22 @@ impl Hook for TelegramHook {
                 "☀️ Job for project *{}* completed successfully.",
                 project_name
             ),
-            Err(ref err) => format!(
-                "💔 Job for project *{}* failed.\n```\n{}\n```",
-                project_name, err
-            ),
+            Err(ref err) => {
+                let output = last_output_lines(job, FAILURE_OUTPUT_LINES);
+                let mut message = format!(
+                    "💔 Job for project *{}* failed.\n```\n{}\n```",
+                    project_name, err
+                );
+
+                if !output.is_empty() {
+                    message.push_str(&format!("\nLast output:\n```\n{}\n```", output.join("\n")));
+                }
+
+                message
+            }
         };
 
-        let send_result = self.api.send_message(&telegram::SendMessageParams {
-            chat_id: &self.chat_id,
-            text: &message,
-            parse_mode: Some(telegram::ParseMode::Markdown),
-            ..Default::default()
-        });
-
-        if let Err(err) = send_result {
-            status!("Unable to send telegram message: {}", err);
-        }
+        self.send_message(&message);
 
         if self.send_log.should_send(result.is_ok()) {
             let path = job_log_path(project_name, job.id);
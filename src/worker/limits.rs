@@ -0,0 +1,221 @@
+use crate::config::LimitsConfig;
+use libc;
+use std::fmt;
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+
+const BYTES_PER_MB: u64 = 1024 * 1024;
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// The cgroup tobyd moves itself into, so that its own cgroup can hold the job cgroups
+const DAEMON_CGROUP: &str = "tobyd";
+
+/// The period of `cpu.max` (in microseconds)
+const CPU_PERIOD_US: u64 = 100_000;
+
+///
+/// A limit that has been detected to be exceeded by a command.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Limit {
+    CpuTime,
+    Memory,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Limit::CpuTime => write!(f, "cpu time"),
+            Limit::Memory => write!(f, "memory"),
+        }
+    }
+}
+
+///
+/// Sets the resource limits of the command before it is executed.
+///
+pub(crate) fn apply_rlimits(command: &mut Command, limits: &LimitsConfig) {
+    // the hard cpu limit is one second above the soft limit, so that the command
+    // receives SIGXCPU (which tells us why it died) instead of SIGKILL
+    let rlimits: Vec<_> = [
+        (libc::RLIMIT_CPU, limits.cpu_time_secs, 1),
+        (
+            libc::RLIMIT_AS,
+            limits.address_space_mb.map(|mb| mb * BYTES_PER_MB),
+            0,
+        ),
+        (libc::RLIMIT_NOFILE, limits.open_files, 0),
+        (libc::RLIMIT_NPROC, limits.processes, 0),
+    ]
+    .iter()
+    .filter_map(|&(resource, value, extra)| {
+        value.map(|value| {
+            let limit = libc::rlimit {
+                rlim_cur: value as libc::rlim_t,
+                rlim_max: (value + extra) as libc::rlim_t,
+            };
+
+            (resource, limit)
+        })
+    })
+    .collect();
+
+    if rlimits.is_empty() {
+        return;
+    }
+
+    command.before_exec(move || {
+        for &(resource, ref limit) in &rlimits {
+            if unsafe { libc::setrlimit(resource, limit) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(())
+    });
+}
+
+///
+/// A cgroup (v2) that limits the memory and cpu usage of a single command.
+/// The cgroup is removed when dropped.
+///
+#[derive(Debug)]
+pub(crate) struct Cgroup {
+    path: PathBuf,
+}
+
+fn own_cgroup() -> io::Result<PathBuf> {
+    let contents = fs::read_to_string("/proc/self/cgroup")?;
+
+    // the cgroup v2 hierarchy is listed as `0::<path>`
+    let relative = contents
+        .lines()
+        .find(|line| line.starts_with("0::"))
+        .map(|line| line[3..].trim_left_matches('/'))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "cgroup v2 is not available"))?;
+
+    Ok(Path::new(CGROUP_ROOT).join(relative))
+}
+
+///
+/// Determines the cgroup that job cgroups are created in (i.e. the cgroup of tobyd).
+///
+/// A cgroup that delegates controllers to its children can't contain processes itself,
+/// so tobyd first moves itself into a child cgroup.
+///
+fn parent_cgroup() -> io::Result<PathBuf> {
+    if !Path::new(CGROUP_ROOT).join("cgroup.controllers").exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "cgroup v2 is not available",
+        ));
+    }
+
+    let mut path = own_cgroup()?;
+
+    if path.ends_with(DAEMON_CGROUP) {
+        path.pop();
+        return Ok(path);
+    }
+
+    let daemon = path.join(DAEMON_CGROUP);
+
+    DirBuilder::new().recursive(true).create(&daemon)?;
+    fs::write(daemon.join("cgroup.procs"), process::id().to_string())?;
+
+    Ok(path)
+}
+
+impl Cgroup {
+    pub(crate) fn create(name: &str, limits: &LimitsConfig) -> io::Result<Self> {
+        let parent = parent_cgroup()?;
+        let path = parent.join(name);
+
+        if limits.memory_mb.is_some() {
+            fs::write(parent.join("cgroup.subtree_control"), "+memory")?;
+        }
+
+        if limits.cpu_percent.is_some() {
+            fs::write(parent.join("cgroup.subtree_control"), "+cpu")?;
+        }
+
+        DirBuilder::new().create(&path)?;
+
+        let cgroup = Cgroup { path };
+
+        if let Some(memory) = limits.memory_mb {
+            fs::write(
+                cgroup.path.join("memory.max"),
+                (memory * BYTES_PER_MB).to_string(),
+            )?;
+        }
+
+        if let Some(percent) = limits.cpu_percent {
+            let quota = CPU_PERIOD_US * percent / 100;
+
+            fs::write(
+                cgroup.path.join("cpu.max"),
+                format!("{} {}", quota, CPU_PERIOD_US),
+            )?;
+        }
+
+        Ok(cgroup)
+    }
+
+    ///
+    /// Moves the command into the cgroup before it is executed.
+    ///
+    pub(crate) fn attach(&self, command: &mut Command) -> io::Result<()> {
+        // the file is opened up front, as the child should only do the bare minimum after forking
+        let procs: File = OpenOptions::new()
+            .write(true)
+            .open(self.path.join("cgroup.procs"))?;
+
+        command.before_exec(move || {
+            // writing 0 moves the writing process
+            let written = unsafe { libc::write(procs.as_raw_fd(), b"0".as_ptr() as *const _, 1) };
+
+            if written != 1 {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(())
+        });
+
+        Ok(())
+    }
+
+    ///
+    /// Whether a process in the cgroup has been killed because it ran out of memory.
+    ///
+    pub(crate) fn oom_killed(&self) -> bool {
+        let events = match fs::read_to_string(self.path.join("memory.events")) {
+            Ok(events) => events,
+            Err(_) => return false,
+        };
+
+        events
+            .lines()
+            .filter_map(|line| {
+                let mut parts = line.split_whitespace();
+
+                match (parts.next(), parts.next()) {
+                    (Some("oom_kill"), Some(count)) => count.parse::<u64>().ok(),
+                    _ => None,
+                }
+            })
+            .any(|count| count > 0)
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        // fails (and leaves the cgroup behind) if background processes are still alive
+        let _ = fs::remove_dir(&self.path);
+    }
+}
@@ -0,0 +1,235 @@
+use crate::config::LogFormat;
+use crate::fs::open_job_log;
+use crate::time::format_timestamp;
+use serde_json;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::SystemTime;
+
+/// Lines of a command's output that are longer are split into several lines (in bytes)
+const MAX_LINE_LENGTH: u64 = 64 * 1024;
+
+///
+/// The source of a line in a job's log.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Stream {
+    Toby,
+    Stdout,
+    Stderr,
+}
+
+///
+/// A single line of a job's log.
+///
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct LogRecord {
+    pub time: String,
+    pub step: Option<String>,
+    pub stream: Stream,
+    pub text: String,
+}
+
+#[derive(Debug)]
+enum Target {
+    Log(File),
+    Terminal,
+}
+
+///
+/// Where the output of a job's commands is written to.
+///
+#[derive(Debug)]
+pub(crate) struct JobOutput {
+    target: Target,
+    format: LogFormat,
+    step: Option<String>,
+}
+
+impl Stream {
+    fn name(self) -> &'static str {
+        match self {
+            Stream::Toby => "toby",
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "toby" => Some(Stream::Toby),
+            "stdout" => Some(Stream::Stdout),
+            "stderr" => Some(Stream::Stderr),
+            _ => None,
+        }
+    }
+}
+
+impl LogRecord {
+    ///
+    /// Formats the record as a line in the given format (without the line break), e.g.:
+    /// `2018-04-02T10:11:12.123Z [stdout] Hello World` or
+    /// `{"time":"2018-04-02T10:11:12.123Z","step":"greet","stream":"stdout","text":"Hello World"}`
+    ///
+    fn format(&self, format: LogFormat) -> String {
+        match format {
+            LogFormat::Text => format!("{} [{}] {}", self.time, self.stream.name(), self.text),
+            LogFormat::Json => serde_json::to_string(self).expect("unable to serialize log record"),
+        }
+    }
+
+    ///
+    /// Parses a line written in either format.
+    /// Text lines don't contain the step, so it is always `None` for them.
+    ///
+    pub(crate) fn parse(line: &str) -> Option<Self> {
+        if line.starts_with('{') {
+            return serde_json::from_str(line).ok();
+        }
+
+        let mut parts = line.splitn(3, ' ');
+        let time = parts.next()?;
+        let stream = parts.next()?;
+        let text = parts.next().unwrap_or("");
+
+        if !stream.starts_with('[') || !stream.ends_with(']') {
+            return None;
+        }
+
+        Some(LogRecord {
+            time: time.into(),
+            step: None,
+            stream: Stream::from_name(&stream[1..stream.len() - 1])?,
+            text: text.into(),
+        })
+    }
+}
+
+impl JobOutput {
+    pub(crate) fn log(file: File, format: LogFormat) -> Self {
+        JobOutput {
+            target: Target::Log(file),
+            format,
+            step: None,
+        }
+    }
+
+    pub(crate) fn terminal() -> Self {
+        JobOutput {
+            target: Target::Terminal,
+            format: LogFormat::Text,
+            step: None,
+        }
+    }
+
+    ///
+    /// Sets the step that subsequent lines belong to.
+    ///
+    pub(crate) fn set_step(&mut self, step: Option<String>) {
+        self.step = step;
+    }
+
+    pub(crate) fn write_line(&mut self, stream: Stream, text: &str) -> io::Result<()> {
+        let record = LogRecord {
+            time: format_timestamp(SystemTime::now()),
+            step: self.step.clone(),
+            stream,
+            text: text.into(),
+        };
+
+        let line = format!("{}\n", record.format(self.format));
+
+        match self.target {
+            Target::Log(ref mut file) => file.write_all(line.as_bytes()),
+            Target::Terminal if stream == Stream::Stderr => io::stderr().write_all(line.as_bytes()),
+            Target::Terminal => io::stdout().write_all(line.as_bytes()),
+        }
+    }
+}
+
+///
+/// Reads lines from a child's pipe on a separate thread and sends them to `sender`.
+/// Long lines are split, so that output without newlines doesn't pile up in memory.
+///
+pub(crate) fn forward_lines<R>(reader: R, stream: Stream, sender: Sender<(Stream, String)>)
+where
+    R: Read + Send + 'static,
+{
+    thread::spawn(move || {
+        let mut reader = BufReader::new(reader);
+        let mut buf = Vec::new();
+
+        loop {
+            buf.clear();
+
+            let mut limited = reader.by_ref().take(MAX_LINE_LENGTH);
+
+            match limited.read_until(b'\n', &mut buf) {
+                Ok(0) | Err(..) => break,
+                Ok(..) => {}
+            };
+
+            while buf.last() == Some(&b'\n') || buf.last() == Some(&b'\r') {
+                buf.pop();
+            }
+
+            let line = String::from_utf8_lossy(&buf).into_owned();
+
+            if sender.send((stream, line)).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+///
+/// Reads and parses the log of a job. Lines that can't be parsed are skipped.
+///
+pub(crate) fn read_job_log(project_name: &str, job_id: u64) -> io::Result<Vec<LogRecord>> {
+    let log = open_job_log(project_name, job_id)?;
+    let mut records = Vec::new();
+
+    for line in BufReader::new(log).lines() {
+        if let Some(record) = LogRecord::parse(&line?) {
+            records.push(record);
+        }
+    }
+
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::sync::mpsc;
+
+    fn forwarded_lines(output: Vec<u8>) -> Vec<String> {
+        let (sender, receiver) = mpsc::channel();
+
+        forward_lines(Cursor::new(output), Stream::Stdout, sender);
+
+        receiver.iter().map(|(_, line)| line).collect()
+    }
+
+    #[test]
+    fn forwards_lines_without_line_endings() {
+        let lines = forwarded_lines(b"first\r\nsecond\n\nlast".to_vec());
+
+        assert_eq!(vec!["first", "second", "", "last"], lines);
+    }
+
+    #[test]
+    fn splits_long_lines() {
+        let length = MAX_LINE_LENGTH as usize;
+        let lines = forwarded_lines(vec![b'a'; length * 2 + 10]);
+
+        assert_eq!(3, lines.len());
+        assert_eq!(length, lines[0].len());
+        assert_eq!(length, lines[1].len());
+        assert_eq!(10, lines[2].len());
+    }
+}
@@ -1,51 +1,199 @@
 mod model;
+mod archive;
+mod artifacts;
 mod context;
+mod environment;
 mod hook;
-
+mod limits;
+mod log;
+mod queue;
+mod source;
+mod state;
+mod workspace;
+
+use self::archive::{write_archive, ArchivedPhase, ArchivedStep, JobReport};
+use self::artifacts::{find_artifacts, store_artifact};
 use self::context::{CommandError, JobContext};
+use self::log::JobOutput;
+use self::queue::{persist_queue, restore_queue};
+use self::source::{checked_out_commit, checkout_scripts, resolve_ref};
+use self::state::ApprovalStatus;
+use self::workspace::JobWorkspace;
+pub(crate) use self::archive::{read_job_archive, ArchivedJob};
+pub(crate) use self::log::{read_job_log, LogRecord};
 pub(crate) use self::model::*;
+pub(crate) use self::queue::{JobQueue, PushError};
+pub(crate) use self::state::{ApprovalError, WorkerState};
 
 use self::hook::{Hook, Hooks};
-use crate::config::{Config, Project};
-use crate::fs::{get_job_archive_file, get_telegram_chat_id};
+use crate::config::{Clean, Config, LogFormat, Project, Projects, Script};
+use crate::fs::{
+    get_job_log, get_telegram_chat_id, lock_groups, next_job_id, try_lock_groups, FileLock,
+};
 use crate::status;
-use crate::time::now;
+use crate::time::{format_duration, now};
+use crate::user::Credentials;
+use std::collections::HashSet;
 use std::fmt;
 use std::io;
-use std::io::Write;
+use std::path::Path;
 use std::slice::SliceConcatExt;
-use toml;
+use std::time::{Duration, Instant};
 
 pub(crate) type JobResult = Result<(), Error>;
 
+/// How often (in seconds) the worker checks whether the lock groups of a waiting job
+/// have been released and whether tobyd is stopping
+const POLL_INTERVAL_SECS: u64 = 1;
+
 #[derive(Debug)]
 pub(crate) enum Error {
     Context(io::Error),
+    Checkout(io::Error),
     Command(CommandError),
     Archive(io::Error),
     Log(io::Error),
+    Lock(io::Error),
+}
+
+#[derive(Debug)]
+pub(crate) enum CancelError {
+    /// The job is running or has already finished
+    NotQueued,
+    Archive(Error),
 }
 
 #[derive(Debug)]
 struct JobRunner<'a> {
     job: &'a Job,
     project: &'a Project,
+    log_format: LogFormat,
+    foreground: bool,
+    approval: Option<Approval>,
+    state: Option<&'a WorkerState>,
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Error::Context(ref err) => write!(f, "Unable to create context: {}", err),
+            Error::Checkout(ref err) => {
+                write!(f, "Unable to determine checked out commit: {}", err)
+            }
             Error::Command(ref err) => write!(f, "{}", err),
             Error::Archive(ref err) => write!(f, "Unable to archive job: {}", err),
             Error::Log(ref err) => write!(f, "Unable write job log: {}", err),
+            Error::Lock(ref err) => write!(f, "Unable to lock lock groups: {}", err),
         }
     }
 }
 
 impl<'a> JobRunner<'a> {
-    fn new(job: &'a Job, project: &'a Project) -> Self {
-        JobRunner { job, project }
+    fn new(
+        job: &'a Job,
+        project: &'a Project,
+        log_format: LogFormat,
+        state: &'a WorkerState,
+    ) -> Self {
+        JobRunner {
+            job,
+            project,
+            log_format,
+            foreground: false,
+            approval: None,
+            state: Some(state),
+        }
+    }
+
+    fn foreground(job: &'a Job, project: &'a Project) -> Self {
+        JobRunner {
+            job,
+            project,
+            log_format: LogFormat::Text,
+            foreground: true,
+            approval: None,
+            state: None,
+        }
+    }
+
+    fn output(&self) -> io::Result<JobOutput> {
+        if self.foreground {
+            Ok(JobOutput::terminal())
+        } else {
+            let file = get_job_log(&self.job.project, self.job.id)?;
+
+            Ok(JobOutput::log(file, self.log_format))
+        }
+    }
+
+    fn dry_run(&self) -> JobResult {
+        let credentials = Credentials::for_project(self.project).map_err(Error::Context)?;
+        let workspace =
+            JobWorkspace::preview(&self.job.project, self.project).map_err(Error::Context)?;
+        let context = JobContext::new(
+            self.job,
+            self.project,
+            workspace,
+            credentials,
+            JobOutput::terminal(),
+            None,
+        );
+
+        println!("Environment:\n{}\n", context);
+
+        let checkout = self.source_scripts(context.workspace_path());
+        let phases = [
+            (Phase::Checkout, &checkout),
+            (Phase::Scripts, &self.project.scripts),
+            (Phase::OnSuccess, &self.project.on_success),
+            (Phase::OnFailure, &self.project.on_failure),
+            (Phase::Finally, &self.project.finally),
+        ];
+
+        for &(phase, scripts) in &phases {
+            if scripts.is_empty() {
+                continue;
+            }
+
+            println!("Commands ({}):", phase);
+
+            for script in scripts {
+                let allow_failure = if script.allow_failure {
+                    " (failure allowed)"
+                } else {
+                    ""
+                };
+
+                let name = match script.name {
+                    Some(ref name) => format!("{}: ", name),
+                    None => String::new(),
+                };
+
+                let when = match script.when {
+                    Some(ref condition) => format!(" (when {})", condition),
+                    None => String::new(),
+                };
+
+                match script.run {
+                    Some(ref run) => {
+                        let shell = script.shell().join(" ");
+
+                        println!("  {}{}{}{}", name, shell, when, allow_failure);
+
+                        for line in run.lines() {
+                            println!("    {}", line);
+                        }
+                    }
+                    None => {
+                        let command = script.command.join(" ");
+
+                        println!("  {}{}{}{}", name, command, when, allow_failure);
+                    }
+                }
+            }
+        }
+
+        Ok(())
     }
 
     fn run(&self) -> JobResult {
@@ -59,36 +207,259 @@ impl<'a> JobRunner<'a> {
             self.job.trigger
         );
 
-        let result = self.run_scripts();
+        let start = Instant::now();
+        let mut report = JobReport {
+            approval: self.approval.clone(),
+            ..Default::default()
+        };
+        let result = self.run_scripts(&mut report);
+
+        report.interrupted = self.state.map_or(false, WorkerState::is_interrupted);
 
-        self.archive_job(started_at, result.is_ok())?;
+        self.archive_job(started_at, start.elapsed(), result.is_ok(), report)?;
 
         result
     }
 
-    fn run_scripts(&self) -> JobResult {
-        let mut context = JobContext::new(self.job, self.project).map_err(Error::Context)?;
+    fn run_scripts(&self, report: &mut JobReport) -> JobResult {
+        let mut context = self.create_context()?;
+
+        println!("{}", context.job_variables());
+
+        let checkout = self.source_scripts(context.workspace_path());
+        let main_result = self
+            .checkout(&mut context, &checkout, report)
+            .and_then(|()| {
+                self.run_phase(&mut context, Phase::Scripts, &self.project.scripts, report)
+            });
+
+        let handler = match main_result {
+            Ok(()) => (Phase::OnSuccess, &self.project.on_success),
+            Err((script, ref err)) => {
+                context.set_failure(&script.display_name(), &err.to_string());
+                (Phase::OnFailure, &self.project.on_failure)
+            }
+        };
+
+        let mut result = main_result.map_err(|(_, err)| err);
+
+        for &(phase, scripts) in &[handler, (Phase::Finally, &self.project.finally)] {
+            if scripts.is_empty() {
+                continue;
+            }
+
+            status!("Running {} scripts", phase);
+
+            context
+                .log(&format!("Running {} scripts", phase))
+                .map_err(Error::Log)?;
+
+            let phase_result = self
+                .run_phase(&mut context, phase, scripts, report)
+                .map_err(|(_, err)| err);
+
+            report.phases.push(ArchivedPhase {
+                phase,
+                successful: phase_result.is_ok(),
+            });
+
+            // a failing on_failure phase doesn't change the outcome of an already failed job
+            if result.is_ok() {
+                result = phase_result;
+            }
+        }
+
+        if !self.project.artifacts.is_empty() {
+            self.collect_artifacts(&mut context, report)
+                .map_err(Error::Log)?;
+        }
+
+        if result.is_err() && self.project.clean == Clean::OnFailure {
+            // the next job starts from scratch instead of building on a broken workspace
+            if let Err(err) = context.clean_workspace() {
+                status!("Unable to clean workspace: {}", err);
+            }
+        }
+
+        result
+    }
+
+    ///
+    /// Copies the files matching the project's artifact patterns out of the workspace.
+    /// Artifacts that can't be collected are reported in the job's log, but don't fail the job.
+    ///
+    fn collect_artifacts(
+        &self,
+        context: &mut JobContext<'a>,
+        report: &mut JobReport,
+    ) -> io::Result<()> {
+        let mut collected = HashSet::new();
+
+        context.log("Collecting artifacts")?;
+
+        for pattern in &self.project.artifacts {
+            let names = match find_artifacts(context.workspace_path(), pattern) {
+                Ok(names) => names,
+                Err(err) => {
+                    context.log(&format!("Unable to collect artifacts {}: {}", pattern, err))?;
+                    continue;
+                }
+            };
+
+            if names.is_empty() {
+                context.log(&format!("No artifacts match {}", pattern))?;
+            }
+
+            for name in names {
+                // files can match several patterns
+                if collected.contains(&name) {
+                    continue;
+                }
+
+                match store_artifact(self.job, context.workspace_path(), &name) {
+                    Ok(artifact) => {
+                        context.log(&format!(
+                            "Collected artifact {} ({} bytes)",
+                            artifact.name, artifact.size
+                        ))?;
+                        report.artifacts.push(artifact);
+                    }
+                    Err(err) => context.log(&format!(
+                        "Unable to collect artifact {}: {}",
+                        name.display(),
+                        err
+                    ))?,
+                }
+
+                collected.insert(name);
+            }
+        }
+
+        Ok(())
+    }
 
-        println!("{}", context);
+    fn create_context(&self) -> Result<JobContext<'a>, Error> {
+        let output = self.output().map_err(Error::Context)?;
+        let credentials = Credentials::for_project(self.project).map_err(Error::Context)?;
+        let workspace =
+            JobWorkspace::acquire(&self.job.project, self.project, credentials.as_ref())
+                .map_err(Error::Context)?;
+
+        Ok(JobContext::new(
+            self.job,
+            self.project,
+            workspace,
+            credentials,
+            output,
+            self.state,
+        ))
+    }
 
-        for script in &self.project.scripts {
-            let command = &script.command;
+    fn source_scripts(&self, workspace: &Path) -> Vec<Script> {
+        match self.project.source {
+            Some(ref source) => checkout_scripts(source, &self.job.revision, workspace),
+            None => Vec::new(),
+        }
+    }
 
-            status!("Running command: {}", command.join(" "));
+    ///
+    /// Checks out the project's source (if configured) and exposes the checked out revision.
+    ///
+    fn checkout<'s>(
+        &self,
+        context: &mut JobContext<'a>,
+        scripts: &'s [Script],
+        report: &mut JobReport,
+    ) -> Result<(), (&'s Script, Error)> {
+        let source = match self.project.source {
+            Some(ref source) => source,
+            None => return Ok(()),
+        };
+
+        self.run_phase(context, Phase::Checkout, scripts, report)?;
+
+        let commit = checked_out_commit(context.workspace_path())
+            .map_err(|err| (&scripts[scripts.len() - 1], Error::Checkout(err)))?;
+        let git_ref = resolve_ref(source, &self.job.revision);
+
+        context
+            .log(&format!("Checked out commit {}", commit))
+            .map_err(|err| (&scripts[scripts.len() - 1], Error::Log(err)))?;
+        context.set_revision(git_ref, &commit);
+
+        report.git_ref = git_ref.map(String::from);
+        report.commit = Some(commit);
 
-            let status = context.run_command(command);
+        Ok(())
+    }
 
-            if let Err(ref err) = status {
-                let result = writeln!(context.log_file(), "[toby] {}", err);
+    ///
+    /// Runs scripts in order until one fails, returning the failed script.
+    ///
+    fn run_phase<'s>(
+        &self,
+        context: &mut JobContext<'a>,
+        phase: Phase,
+        scripts: &'s [Script],
+        report: &mut JobReport,
+    ) -> Result<(), (&'s Script, Error)> {
+        for script in scripts {
+            let name = script.display_name();
+            let summary = script.summary();
+
+            if !context.should_run(script) {
+                status!("Skipping command: {}", summary);
+
+                context
+                    .log(&format!("==> skip {}: condition not met", name))
+                    .map_err(|err| (script, Error::Log(err)))?;
+                report.steps.push(ArchivedStep::skipped(phase, script));
+
+                continue;
+            }
 
-                result.map_err(Error::Log)?;
+            status!("Running command: {}", summary);
+
+            context.set_step(Some(name.clone()));
+            context
+                .log(&format!("==> begin {}: {}", name, summary))
+                .map_err(|err| (script, Error::Log(err)))?;
+
+            let started_at = now();
+            let start = Instant::now();
+            let (status, attempts) = self
+                .run_attempts(context, phase, script)
+                .map_err(|err| (script, Error::Log(err)))?;
+            let duration = start.elapsed();
+
+            let outcome = match status {
+                Ok(()) => "successful".to_string(),
+                Err(ref err) => err.to_string(),
+            };
+
+            context
+                .log(&format!(
+                    "<== end {}: {} ({})",
+                    name,
+                    outcome,
+                    format_duration(duration)
+                ))
+                .map_err(|err| (script, Error::Log(err)))?;
+            context.set_step(None);
+
+            report.steps.push(ArchivedStep::new(
+                phase, script, started_at, duration, &status, attempts,
+            ));
+
+            if status.is_err() {
+                context.mark_failed();
             }
 
             status.map_err(Error::Command).or_else(|err| {
                 if script.allow_failure {
                     Ok(())
                 } else {
-                    Err(err)
+                    Err((script, err))
                 }
             })?;
         }
@@ -96,34 +467,291 @@ impl<'a> JobRunner<'a> {
         Ok(())
     }
 
-    fn archive_job(&self, started_at: u64, successful: bool) -> JobResult {
-        let job = &self.job;
+    ///
+    /// Runs a script, retrying failed attempts as configured.
+    /// Returns the result of the last attempt and the number of attempts.
+    ///
+    fn run_attempts(
+        &self,
+        context: &mut JobContext<'a>,
+        phase: Phase,
+        script: &Script,
+    ) -> io::Result<(Result<(), CommandError>, u32)> {
+        let attempts = script.retries + 1;
+        let mut attempt = 1;
+
+        loop {
+            if attempts > 1 {
+                context.log(&format!("--> attempt {} of {}", attempt, attempts))?;
+            }
 
-        let file = get_job_archive_file(&job.project, job.id).map_err(Error::Archive)?;
-        let mut buf_writer = io::BufWriter::new(file);
-        let archived_job = self.job.archive(started_at, successful);
-        let archived_job_str = toml::to_string(&archived_job).expect("unable to serialize job");
+            let status = context.run_script(phase, script);
 
-        buf_writer
-            .write_all(archived_job_str.as_bytes())
-            .map_err(Error::Archive)?;
+            let failure = match status {
+                Err(ref err)
+                    if attempt < attempts
+                        && script.should_retry(err.exit_code())
+                        && !context.is_interrupted() =>
+                {
+                    Some(err.to_string())
+                }
+                _ => None,
+            };
 
-        Ok(())
+            let failure = match failure {
+                Some(failure) => failure,
+                None => return Ok((status, attempt)),
+            };
+
+            let delay = script.retry_delay(attempt);
+
+            status!("Attempt {} of {} failed, retrying", attempt, attempts);
+
+            context.log(&format!(
+                "<-- attempt {} of {} failed: {}, retrying in {}",
+                attempt,
+                attempts,
+                failure,
+                format_duration(delay)
+            ))?;
+
+            if !context.wait_before_retry(delay) {
+                context.log("<-- retry aborted, tobyd is shutting down")?;
+                return Ok((status, attempt));
+            }
+
+            attempt += 1;
+        }
+    }
+
+    fn archive_job(
+        &self,
+        started_at: u64,
+        duration: Duration,
+        successful: bool,
+        report: JobReport,
+    ) -> JobResult {
+        let archived_job = self.job.archive(started_at, duration, successful, report);
+
+        write_archive(self.job, &archived_job)
     }
 }
 
-pub(crate) fn start_worker(config: &Config, receiver: &WorkerReceiver) {
+///
+/// Runs a job in the foreground. The output of its commands is written to the terminal
+/// instead of the job's log file and no hooks are called.
+///
+/// Waits until the project's lock groups are no longer held by other jobs.
+///
+pub(crate) fn run_job_foreground(job: &Job, project: &Project) -> JobResult {
+    let _locks = lock_groups(&project.lock_groups).map_err(Error::Lock)?;
+
+    JobRunner::foreground(job, project).run()
+}
+
+///
+/// Prints the environment and commands of a job without running it.
+///
+pub(crate) fn dry_run_job(job: &Job, project: &Project) -> JobResult {
+    JobRunner::foreground(job, project).dry_run()
+}
+
+///
+/// Removes a queued job from the queue and archives it as cancelled.
+///
+pub(crate) fn cancel_job(
+    queue: &JobQueue,
+    state: &WorkerState,
+    project_name: &str,
+    job_id: JobId,
+) -> Result<(), CancelError> {
+    let job = queue
+        .remove(project_name, job_id)
+        .ok_or(CancelError::NotQueued)?;
+
+    state.discard_approval(&job);
+
+    status!("Job #{} for {} was cancelled", job.id, project_name);
+
+    write_archive(&job, &job.archive_cancelled(now())).map_err(CancelError::Archive)
+}
+
+///
+/// A job taken from the queue, with the decision on its approval (if its project requires one)
+/// and the locks of its project's lock groups.
+///
+type RunnableJob = (Job, Option<Approval>, Vec<FileLock>);
+
+///
+/// Removes the next job that can run now from the queue.
+///
+/// Jobs that await approval, or whose lock groups are held by another job (possibly of another
+/// tobyd instance), are passed over, but never in favor of a later job of the same project.
+/// Jobs whose approval has just been requested are added to `awaiting_approval`.
+///
+fn take_runnable_job(
+    queue: &JobQueue,
+    projects: &Projects,
+    state: &WorkerState,
+    awaiting_approval: &mut Vec<Job>,
+) -> Option<RunnableJob> {
+    let (job, (approval, locks)) = queue.take(|job| {
+        let project = match projects.get(&job.project) {
+            Some(project) => project,
+            None => return Some((None, Vec::new())),
+        };
+
+        let approval = match project.approval {
+            Some(ref approval_config) => {
+                match state.poll_approval(job, approval_config.timeout()) {
+                    ApprovalStatus::Requested => {
+                        awaiting_approval.push(job.clone());
+                        return None;
+                    }
+                    ApprovalStatus::Pending => return None,
+                    // rejected jobs are archived without running
+                    ApprovalStatus::Decided(ref approval) if !approval.approved => {
+                        return Some((Some(approval.clone()), Vec::new()));
+                    }
+                    ApprovalStatus::Decided(approval) => Some(approval),
+                }
+            }
+            None => None,
+        };
+
+        match try_lock_groups(&project.lock_groups) {
+            Ok(locks) => locks.map(|locks| (approval, locks)),
+            Err(err) => {
+                status!(
+                    "Unable to lock lock groups for job #{} for {}: {}",
+                    job.id,
+                    job.project,
+                    err
+                );
+                None
+            }
+        }
+    })?;
+
+    Some((job, approval, locks))
+}
+
+///
+/// Creates jobs for the projects that are triggered by a finished job.
+///
+fn downstream_jobs(
+    job: &Job,
+    project: &Project,
+    projects: &Projects,
+    successful: bool,
+) -> Vec<Job> {
+    project
+        .downstream(successful)
+        .iter()
+        .filter_map(|downstream| match next_job_id(downstream) {
+            Ok(id) => {
+                status!("Triggering job #{} for {}", id, downstream);
+
+                Some(Job {
+                    id,
+                    project: downstream.clone(),
+                    priority: projects
+                        .get(downstream)
+                        .map_or(0, |downstream| downstream.priority),
+                    trigger: JobTrigger::Upstream {
+                        project: job.project.clone(),
+                        id: job.id,
+                    },
+                    revision: Revision::default(),
+                    parameters: Parameters::new(),
+                })
+            }
+            Err(err) => {
+                status!("Unable to trigger job for {}: {}", downstream, err);
+                None
+            }
+        })
+        .collect()
+}
+
+pub(crate) fn start_worker(config: &Config, queue: &JobQueue, state: &WorkerState) {
     let projects = &config.projects;
 
     let telegram_chat_id = get_telegram_chat_id().expect("Unable to read telegram chat id");
     let hooks = Hooks::from_config(config, telegram_chat_id);
 
-    for job in receiver {
+    match restore_queue() {
+        Ok(jobs) => {
+            if !jobs.is_empty() {
+                status!("Restored {} queued jobs", jobs.len());
+            }
+
+            queue.extend(jobs);
+        }
+        Err(err) => status!("Unable to restore queued jobs: {}", err),
+    }
+
+    let poll_interval = Duration::from_secs(POLL_INTERVAL_SECS);
+
+    loop {
+        if state.is_stopping() {
+            let jobs = queue.close();
+            let count = jobs.len();
+
+            match persist_queue(jobs) {
+                Ok(()) if count > 0 => status!("Saved {} queued jobs", count),
+                Ok(()) => {}
+                Err(err) => status!("Unable to save queued jobs: {}", err),
+            }
+
+            break;
+        }
+
+        let mut awaiting_approval = Vec::new();
+        let runnable = take_runnable_job(queue, projects, state, &mut awaiting_approval);
+
+        for job in &awaiting_approval {
+            status!("Job #{} for {} is awaiting approval", job.id, job.project);
+
+            hooks.awaiting_approval(job);
+        }
+
+        let (job, approval, locks) = match runnable {
+            Some(runnable) => runnable,
+            None => {
+                // wait for a new job, for a decision on an approval, for another job to release
+                // its locks or for tobyd to stop
+                queue.wait(poll_interval);
+                continue;
+            }
+        };
+
+        // the locks are held until the job is finished
+        let _locks = locks;
+
         let project_name = &job.project;
 
+        state.start(&job);
+
         match projects.get(project_name) {
             Some(project) => {
-                let runner = JobRunner::new(&job, project);
+                let mut runner =
+                    JobRunner::new(&job, project, project.log_format(&config.main), state);
+
+                if let Some(approval) = approval {
+                    if !approval.approved {
+                        status!("Job #{} for {} was rejected", job.id, project_name);
+
+                        if let Err(err) = write_archive(&job, &job.archive_rejected(approval)) {
+                            status!("{}", err);
+                        }
+
+                        state.finish();
+                        continue;
+                    }
+
+                    runner.approval = Some(approval);
+                }
 
                 hooks.before_job(&job);
 
@@ -135,8 +763,14 @@ pub(crate) fn start_worker(config: &Config, receiver: &WorkerReceiver) {
                 };
 
                 hooks.after_job(&job, &job_result);
+
+                queue.insert_next(downstream_jobs(&job, project, projects, job_result.is_ok()));
             }
             None => status!("Project {} does not exist", project_name),
         }
+
+        state.finish();
     }
+
+    state.mark_stopped();
 }
@@ -1,22 +1,52 @@
+use std::collections::BTreeMap;
 use std::fmt;
-use std::sync::mpsc::{Receiver, SyncSender};
 
 pub(crate) type JobId = u64;
-pub(crate) type WorkerSender = SyncSender<Job>;
-pub(crate) type WorkerReceiver = Receiver<Job>;
 
-#[derive(Debug)]
+/// Parameters passed in by the trigger of a job, which can be referred to by `when` conditions
+pub(crate) type Parameters = BTreeMap<String, String>;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub(crate) struct Job {
     pub id: JobId,
     pub project: String,
+    /// Jobs with a higher priority run first
+    #[serde(default)]
+    pub priority: i32,
     pub trigger: JobTrigger,
+    pub revision: Revision,
+    pub parameters: Parameters,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub(crate) struct ArchivedJob {
-    pub started_at: u64,
-    pub successful: bool,
-    pub trigger: JobTrigger,
+///
+/// The ref and/or commit of the project's source a job was triggered for.
+/// Unset fields fall back to the project's `[source]` config.
+///
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub(crate) struct Revision {
+    pub git_ref: Option<String>,
+    pub commit: Option<String>,
+}
+
+///
+/// The decision on a job of a project that requires approval.
+///
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub(crate) struct Approval {
+    pub approved: bool,
+    /// The token that approved or rejected the job, unset if the approval timed out
+    pub by: Option<String>,
+    pub decided_at: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Phase {
+    Checkout,
+    Scripts,
+    OnSuccess,
+    OnFailure,
+    Finally,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -24,6 +54,35 @@ pub(crate) struct ArchivedJob {
 pub(crate) enum JobTrigger {
     Webhook { token: String },
     Telegram { username: String },
+    Cli { user: String },
+    Schedule { schedule: String },
+    Upstream { project: String, id: JobId },
+}
+
+fn is_valid_ref(git_ref: &str) -> bool {
+    // refs are passed to git as arguments, so they must not look like options
+    !git_ref.is_empty()
+        && !git_ref.starts_with('-')
+        && !git_ref.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+fn is_valid_commit(commit: &str) -> bool {
+    commit.len() >= 4 && commit.len() <= 64 && commit.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+///
+/// Parameter names are exposed as parts of environment variable names,
+/// so they are restricted to alphanumeric characters and underscores.
+///
+pub(crate) fn is_valid_parameter_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+impl Revision {
+    pub(crate) fn is_valid(&self) -> bool {
+        self.git_ref.iter().all(|git_ref| is_valid_ref(git_ref))
+            && self.commit.iter().all(|commit| is_valid_commit(commit))
+    }
 }
 
 impl JobTrigger {
@@ -31,6 +90,9 @@ impl JobTrigger {
         match *self {
             JobTrigger::Webhook { .. } => "webhook",
             JobTrigger::Telegram { .. } => "telegram",
+            JobTrigger::Cli { .. } => "cli",
+            JobTrigger::Schedule { .. } => "schedule",
+            JobTrigger::Upstream { .. } => "upstream",
         }
     }
 }
@@ -40,16 +102,25 @@ impl fmt::Display for JobTrigger {
         match *self {
             JobTrigger::Webhook { ref token } => write!(f, "webhook ({})", token),
             JobTrigger::Telegram { ref username } => write!(f, "telegram user {}", username),
+            JobTrigger::Cli { ref user } => write!(f, "toby run ({})", user),
+            JobTrigger::Schedule { ref schedule } => write!(f, "schedule ({})", schedule),
+            JobTrigger::Upstream { ref project, id } => {
+                write!(f, "job #{} of project {}", id, project)
+            }
         }
     }
 }
 
-impl Job {
-    pub(crate) fn archive(&self, started_at: u64, successful: bool) -> ArchivedJob {
-        ArchivedJob {
-            trigger: self.trigger.clone(),
-            started_at,
-            successful,
-        }
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Phase::Checkout => "checkout",
+            Phase::Scripts => "main",
+            Phase::OnSuccess => "on_success",
+            Phase::OnFailure => "on_failure",
+            Phase::Finally => "finally",
+        };
+
+        write!(f, "{}", name)
     }
 }
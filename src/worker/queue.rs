@@ -0,0 +1,385 @@
+use super::model::{Job, JobId};
+use crate::fs::{take_queue, write_queue};
+use std::collections::HashSet;
+use std::io;
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+use toml;
+
+///
+/// Jobs waiting to be run, shared between the worker and everything that triggers jobs.
+///
+#[derive(Debug, Default)]
+pub(crate) struct JobQueue {
+    inner: Mutex<Inner>,
+    job_added: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// In the order the jobs were queued
+    jobs: Vec<Job>,
+    /// Set once tobyd is stopping
+    closed: bool,
+}
+
+#[derive(Debug)]
+pub(crate) enum PushError {
+    Closed,
+    Io(io::Error),
+}
+
+///
+/// The jobs that were still queued when tobyd stopped.
+///
+#[derive(Debug, Serialize, Deserialize)]
+struct PersistedQueue {
+    jobs: Vec<Job>,
+}
+
+///
+/// The order jobs run in (as indices into the queued jobs): the job with the highest priority
+/// runs first, but never before an earlier job of the same project.
+/// Jobs with the same priority run in the order they were queued.
+///
+fn run_order(jobs: &[Job]) -> Vec<usize> {
+    let mut order = Vec::with_capacity(jobs.len());
+    let mut ordered = vec![false; jobs.len()];
+
+    while order.len() < jobs.len() {
+        let mut projects = HashSet::new();
+        let mut next: Option<usize> = None;
+
+        for (index, job) in jobs.iter().enumerate() {
+            // only the first remaining job of each project can run next
+            if ordered[index] || !projects.insert(job.project.as_str()) {
+                continue;
+            }
+
+            if next.map_or(true, |next| job.priority > jobs[next].priority) {
+                next = Some(index);
+            }
+        }
+
+        let next = next.expect("no job left to order");
+
+        ordered[next] = true;
+        order.push(next);
+    }
+
+    order
+}
+
+impl JobQueue {
+    pub(crate) fn new() -> Self {
+        Default::default()
+    }
+
+    ///
+    /// Queues the job returned by `create`, which is called while the queue is locked,
+    /// so that no job id is used up by a job that can't be queued anymore.
+    ///
+    pub(crate) fn push<F>(&self, create: F) -> Result<JobId, PushError>
+    where
+        F: FnOnce() -> io::Result<Job>,
+    {
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.closed {
+            return Err(PushError::Closed);
+        }
+
+        let job = create().map_err(PushError::Io)?;
+        let id = job.id;
+
+        inner.jobs.push(job);
+
+        self.job_added.notify_all();
+
+        Ok(id)
+    }
+
+    ///
+    /// Queues jobs that were restored after tobyd has been restarted.
+    ///
+    pub(crate) fn extend<I>(&self, jobs: I)
+    where
+        I: IntoIterator<Item = Job>,
+    {
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.jobs.extend(jobs);
+
+        self.job_added.notify_all();
+    }
+
+    ///
+    /// Queues jobs triggered by the job that just finished ahead of the jobs that are already
+    /// waiting. A job still runs after the jobs of its own project that were queued earlier.
+    ///
+    pub(crate) fn insert_next<I>(&self, jobs: I)
+    where
+        I: IntoIterator<Item = Job>,
+    {
+        let mut inner = self.inner.lock().unwrap();
+        let mut front = 0;
+
+        for job in jobs {
+            let index = inner
+                .jobs
+                .iter()
+                .rposition(|queued| queued.project == job.project)
+                .map_or(front, |last| (last + 1).max(front));
+
+            inner.jobs.insert(index, job);
+            front = index + 1;
+        }
+
+        self.job_added.notify_all();
+    }
+
+    ///
+    /// Removes the first job (in run order) that `runnable` returns a value for.
+    ///
+    /// Once a job has been passed over, later jobs of the same project are passed over as well.
+    ///
+    pub(crate) fn take<F, T>(&self, mut runnable: F) -> Option<(Job, T)>
+    where
+        F: FnMut(&Job) -> Option<T>,
+    {
+        let mut inner = self.inner.lock().unwrap();
+        let mut taken = None;
+
+        {
+            let queued = &inner.jobs;
+            let mut passed_over = HashSet::new();
+
+            for index in run_order(queued) {
+                let job = &queued[index];
+
+                if passed_over.contains(job.project.as_str()) {
+                    continue;
+                }
+
+                match runnable(job) {
+                    Some(value) => {
+                        taken = Some((index, value));
+                        break;
+                    }
+                    None => {
+                        passed_over.insert(job.project.as_str());
+                    }
+                }
+            }
+        }
+
+        taken.map(|(index, value)| (inner.jobs.remove(index), value))
+    }
+
+    ///
+    /// Removes a queued job. Returns `None` if the job isn't queued.
+    ///
+    pub(crate) fn remove(&self, project: &str, id: JobId) -> Option<Job> {
+        let mut inner = self.inner.lock().unwrap();
+        let index = inner
+            .jobs
+            .iter()
+            .position(|job| job.project == project && job.id == id)?;
+
+        Some(inner.jobs.remove(index))
+    }
+
+    ///
+    /// Blocks until a job has been added or the timeout has passed.
+    ///
+    pub(crate) fn wait(&self, timeout: Duration) {
+        let inner = self.inner.lock().unwrap();
+        let _ = self.job_added.wait_timeout(inner, timeout).unwrap();
+    }
+
+    ///
+    /// The queued jobs in the order they will run in.
+    ///
+    pub(crate) fn pending(&self) -> Vec<Job> {
+        let inner = self.inner.lock().unwrap();
+
+        run_order(&inner.jobs)
+            .into_iter()
+            .map(|index| inner.jobs[index].clone())
+            .collect()
+    }
+
+    ///
+    /// Stops accepting new jobs and removes all jobs from the queue,
+    /// in the order they were queued.
+    ///
+    pub(crate) fn close(&self) -> Vec<Job> {
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.closed = true;
+        inner.jobs.drain(..).collect()
+    }
+}
+
+///
+/// Saves the queued jobs, so that they run after tobyd has been restarted.
+///
+pub(crate) fn persist_queue<I>(jobs: I) -> io::Result<()>
+where
+    I: IntoIterator<Item = Job>,
+{
+    let queue = PersistedQueue {
+        jobs: jobs.into_iter().collect(),
+    };
+
+    if queue.jobs.is_empty() {
+        return Ok(());
+    }
+
+    let contents = toml::to_string(&queue).expect("unable to serialize queue");
+
+    write_queue(&contents)
+}
+
+///
+/// Takes the jobs that were saved when tobyd stopped.
+///
+pub(crate) fn restore_queue() -> io::Result<Vec<Job>> {
+    let contents = match take_queue()? {
+        Some(contents) => contents,
+        None => return Ok(Vec::new()),
+    };
+
+    let queue: PersistedQueue = toml::from_str(&contents)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+
+    Ok(queue.jobs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::worker::JobTrigger;
+
+    fn job(project: &str, id: JobId, priority: i32) -> Job {
+        Job {
+            id,
+            project: project.into(),
+            priority,
+            trigger: JobTrigger::Cli {
+                user: "root".into(),
+            },
+            revision: Default::default(),
+            parameters: Default::default(),
+        }
+    }
+
+    fn ids(queue: &JobQueue) -> Vec<(String, JobId)> {
+        queue
+            .pending()
+            .into_iter()
+            .map(|job| (job.project, job.id))
+            .collect()
+    }
+
+    fn queue(jobs: Vec<Job>) -> JobQueue {
+        let queue = JobQueue::new();
+
+        queue.extend(jobs);
+
+        queue
+    }
+
+    #[test]
+    fn equal_priorities_keep_submission_order() {
+        let jobs = vec![
+            job("a", 1, 0),
+            job("b", 1, 0),
+            job("a", 2, 0),
+            job("c", 1, 0),
+        ];
+
+        assert_eq!(vec![0, 1, 2, 3], run_order(&jobs));
+    }
+
+    #[test]
+    fn higher_priority_moves_ahead_of_waiting_jobs() {
+        let jobs = vec![
+            job("a", 1, 0),
+            job("b", 1, 0),
+            job("c", 1, 10),
+            job("d", 1, -1),
+        ];
+
+        assert_eq!(vec![2, 0, 1, 3], run_order(&jobs));
+    }
+
+    #[test]
+    fn higher_priority_never_overtakes_jobs_of_its_project() {
+        let jobs = vec![job("a", 1, 0), job("b", 1, 5), job("a", 2, 10)];
+
+        assert_eq!(vec![1, 0, 2], run_order(&jobs));
+    }
+
+    #[test]
+    fn pushed_jobs_are_taken_in_run_order() {
+        let queue = queue(vec![job("a", 1, 0), job("b", 1, 0)]);
+
+        queue.push(|| Ok(job("c", 1, 1))).unwrap();
+
+        let (first, ()) = queue.take(|_| Some(())).unwrap();
+
+        assert_eq!(("c".to_string(), 1), (first.project, first.id));
+        assert_eq!(
+            vec![("a".to_string(), 1), ("b".to_string(), 1)],
+            ids(&queue)
+        );
+    }
+
+    #[test]
+    fn passing_over_a_job_passes_over_its_project() {
+        let queue = queue(vec![job("a", 1, 0), job("a", 2, 0), job("b", 1, 0)]);
+
+        let (taken, ()) = queue
+            .take(|job| {
+                if job.id == 1 && job.project == "a" {
+                    None
+                } else {
+                    Some(())
+                }
+            })
+            .unwrap();
+
+        assert_eq!(("b".to_string(), 1), (taken.project, taken.id));
+    }
+
+    #[test]
+    fn downstream_jobs_run_next_but_after_their_project() {
+        let queue = queue(vec![job("a", 1, 0), job("b", 1, 0), job("c", 1, 0)]);
+
+        queue.insert_next(vec![job("d", 1, 0), job("b", 2, 0)]);
+
+        assert_eq!(
+            vec![
+                ("d".to_string(), 1),
+                ("a".to_string(), 1),
+                ("b".to_string(), 1),
+                ("b".to_string(), 2),
+                ("c".to_string(), 1),
+            ],
+            ids(&queue)
+        );
+    }
+
+    #[test]
+    fn closed_queue_rejects_jobs() {
+        let queue = queue(vec![job("a", 1, 0)]);
+
+        assert_eq!(1, queue.close().len());
+        assert!(match queue.push(|| Ok(job("a", 2, 0))) {
+            Err(PushError::Closed) => true,
+            _ => false,
+        });
+        assert!(queue.pending().is_empty());
+    }
+}
@@ -0,0 +1,102 @@
+use super::model::Revision;
+use crate::config::{Script, SourceConfig};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+fn script(name: String, command: &[&str]) -> Script {
+    Script {
+        name: Some(name),
+        command: command.iter().map(|arg| arg.to_string()).collect(),
+        run: None,
+        shell: None,
+        allow_failure: false,
+        when: None,
+        retries: 0,
+        retry_delay: None,
+        retry_on: Vec::new(),
+    }
+}
+
+///
+/// The ref that is checked out, preferring the one passed in by the trigger.
+///
+pub(crate) fn resolve_ref<'a>(source: &'a SourceConfig, revision: &'a Revision) -> Option<&'a str> {
+    revision
+        .git_ref
+        .as_ref()
+        .or(source.git_ref.as_ref())
+        .map(String::as_str)
+}
+
+///
+/// Builds the git commands that check out the source of a project into the workspace.
+///
+/// Instead of cloning, the repository is fetched into the (possibly persistent) workspace,
+/// so that subsequent jobs only need to fetch new objects.
+///
+pub(crate) fn checkout_scripts(
+    source: &SourceConfig,
+    revision: &Revision,
+    workspace: &Path,
+) -> Vec<Script> {
+    let repository = source.repository.as_str();
+    let target = match revision.commit {
+        Some(ref commit) => commit.as_str(),
+        None => resolve_ref(source, revision).unwrap_or("HEAD"),
+    };
+    let depth = source.depth.map(|depth| depth.to_string());
+    let mut scripts = Vec::new();
+
+    if workspace.join(".git").exists() {
+        scripts.push(script(
+            "Update repository url".into(),
+            &["git", "remote", "set-url", "origin", repository],
+        ));
+    } else {
+        scripts.push(script(
+            "Initialize repository".into(),
+            &["git", "init", "--quiet"],
+        ));
+        scripts.push(script(
+            "Add repository".into(),
+            &["git", "remote", "add", "origin", repository],
+        ));
+    }
+
+    let mut fetch = vec!["git", "fetch", "--quiet"];
+
+    if let Some(ref depth) = depth {
+        fetch.extend(&["--depth", depth.as_str()]);
+    }
+
+    fetch.extend(&["origin", target]);
+
+    scripts.push(script(format!("Fetch {}", target), &fetch));
+    scripts.push(script(
+        format!("Check out {}", target),
+        &["git", "checkout", "--quiet", "--force", "FETCH_HEAD"],
+    ));
+
+    if source.submodules {
+        let mut update = vec!["git", "submodule", "update", "--init", "--recursive"];
+
+        if let Some(ref depth) = depth {
+            update.extend(&["--depth", depth.as_str()]);
+        }
+
+        scripts.push(script("Update submodules".into(), &update));
+    }
+
+    scripts
+}
+
+///
+/// Reads the commit that has been checked out. As `FETCH_HEAD` is checked out,
+/// `HEAD` is always detached and contains the commit hash.
+///
+pub(crate) fn checked_out_commit(workspace: &Path) -> io::Result<String> {
+    let head = fs::read_to_string(workspace.join(".git").join("HEAD"))?;
+
+    Ok(head.trim().to_string())
+}
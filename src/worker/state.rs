@@ -0,0 +1,298 @@
+use super::model::{Approval, Job, JobId};
+use crate::time::now;
+use libc;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+type JobKey = (String, JobId);
+
+///
+/// State shared between the worker and the http server.
+///
+#[derive(Debug, Default)]
+pub(crate) struct WorkerState {
+    inner: Mutex<Inner>,
+    worker_stopped: Condvar,
+    shutdown_requested: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    running: Option<JobKey>,
+    /// Jobs whose approval has been requested, until they are started
+    approvals: HashMap<JobKey, PendingApproval>,
+    /// The process group of the command the running job is executing
+    process_group: Option<u32>,
+    stopping: bool,
+    interrupted: bool,
+    stopped: bool,
+}
+
+#[derive(Debug)]
+struct PendingApproval {
+    deadline: Option<Instant>,
+    decision: Option<Approval>,
+}
+
+#[derive(Debug)]
+pub(crate) enum ApprovalStatus {
+    /// The approval has just been requested
+    Requested,
+    Pending,
+    Decided(Approval),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ApprovalError {
+    NotAwaitingApproval,
+}
+
+fn key(project: &str, id: JobId) -> JobKey {
+    (project.to_string(), id)
+}
+
+fn terminate_process_group(process_group: u32) {
+    unsafe {
+        libc::kill(-(process_group as libc::pid_t), libc::SIGTERM);
+    }
+}
+
+impl WorkerState {
+    pub(crate) fn new() -> Self {
+        Default::default()
+    }
+
+    pub(crate) fn is_running(&self, project: &str, id: JobId) -> bool {
+        let inner = self.inner.lock().unwrap();
+
+        inner.running == Some(key(project, id))
+    }
+
+    pub(crate) fn is_awaiting_approval(&self, project: &str, id: JobId) -> bool {
+        let inner = self.inner.lock().unwrap();
+
+        inner
+            .approvals
+            .get(&key(project, id))
+            .map_or(false, |pending| pending.decision.is_none())
+    }
+
+    pub(crate) fn start(&self, job: &Job) {
+        let mut inner = self.inner.lock().unwrap();
+        let key = key(&job.project, job.id);
+
+        inner.approvals.remove(&key);
+        inner.running = Some(key);
+    }
+
+    ///
+    /// Forgets the approval of a job that has been removed from the queue without running.
+    ///
+    pub(crate) fn discard_approval(&self, job: &Job) {
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.approvals.remove(&key(&job.project, job.id));
+    }
+
+    ///
+    /// Checks whether a job of a project that requires approval has been approved or rejected.
+    /// The first check requests the approval. Jobs that haven't been approved within the timeout
+    /// (counted from the request) are rejected.
+    ///
+    /// The decision is kept until the job is started.
+    ///
+    pub(crate) fn poll_approval(&self, job: &Job, timeout: Option<Duration>) -> ApprovalStatus {
+        let mut inner = self.inner.lock().unwrap();
+        let key = key(&job.project, job.id);
+
+        let pending = match inner.approvals.entry(key) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                entry.insert(PendingApproval {
+                    deadline: timeout.map(|timeout| Instant::now() + timeout),
+                    decision: None,
+                });
+
+                return ApprovalStatus::Requested;
+            }
+        };
+
+        if pending.decision.is_none() && pending.deadline.map_or(false, |d| Instant::now() >= d) {
+            pending.decision = Some(Approval {
+                approved: false,
+                by: None,
+                decided_at: now(),
+            });
+        }
+
+        match pending.decision {
+            Some(ref approval) => ApprovalStatus::Decided(approval.clone()),
+            None => ApprovalStatus::Pending,
+        }
+    }
+
+    ///
+    /// Approves or rejects a job that is awaiting approval.
+    ///
+    pub(crate) fn decide(
+        &self,
+        project: &str,
+        id: JobId,
+        approved: bool,
+        by: &str,
+    ) -> Result<(), ApprovalError> {
+        let mut inner = self.inner.lock().unwrap();
+
+        let pending = match inner.approvals.get_mut(&key(project, id)) {
+            Some(pending) => pending,
+            None => return Err(ApprovalError::NotAwaitingApproval),
+        };
+
+        // a job can only be decided on once
+        if pending.decision.is_some() {
+            return Err(ApprovalError::NotAwaitingApproval);
+        }
+
+        pending.decision = Some(Approval {
+            approved,
+            by: Some(by.into()),
+            decided_at: now(),
+        });
+
+        Ok(())
+    }
+
+    pub(crate) fn finish(&self) {
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.running = None;
+    }
+
+    ///
+    /// Records the process group of the command the running job has started.
+    /// The command is terminated right away if the job has already been interrupted.
+    ///
+    pub(crate) fn enter_process_group(&self, process_group: u32) {
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.process_group = Some(process_group);
+
+        if inner.interrupted {
+            terminate_process_group(process_group);
+        }
+    }
+
+    pub(crate) fn leave_process_group(&self) {
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.process_group = None;
+    }
+
+    ///
+    /// Tells the worker to stop once the running job has finished.
+    /// New jobs are no longer accepted.
+    ///
+    pub(crate) fn stop(&self) {
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.stopping = true;
+
+        self.shutdown_requested.notify_all();
+    }
+
+    pub(crate) fn is_stopping(&self) -> bool {
+        let inner = self.inner.lock().unwrap();
+
+        inner.stopping
+    }
+
+    ///
+    /// Interrupts the running job by sending SIGTERM to the process group of its command.
+    /// Its remaining commands fail without being run.
+    ///
+    pub(crate) fn interrupt(&self) {
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.interrupted = true;
+
+        if let Some(process_group) = inner.process_group {
+            terminate_process_group(process_group);
+        }
+
+        self.shutdown_requested.notify_all();
+    }
+
+    pub(crate) fn is_interrupted(&self) -> bool {
+        let inner = self.inner.lock().unwrap();
+
+        inner.interrupted
+    }
+
+    ///
+    /// Blocks for the given duration, unless tobyd is stopping.
+    /// Returns `false` if it has been woken up because tobyd is stopping.
+    ///
+    pub(crate) fn sleep(&self, duration: Duration) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let deadline = Instant::now() + duration;
+
+        loop {
+            if inner.stopping || inner.interrupted {
+                return false;
+            }
+
+            let current = Instant::now();
+
+            if current >= deadline {
+                return true;
+            }
+
+            inner = self
+                .shutdown_requested
+                .wait_timeout(inner, deadline - current)
+                .unwrap()
+                .0;
+        }
+    }
+
+    ///
+    /// Called by the worker once it has stopped.
+    ///
+    pub(crate) fn mark_stopped(&self) {
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.stopped = true;
+
+        self.worker_stopped.notify_all();
+    }
+
+    ///
+    /// Blocks until the worker has stopped. Returns `false` if it hasn't stopped within the timeout.
+    ///
+    pub(crate) fn await_worker_stopped(&self, timeout: Option<Duration>) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+        while !inner.stopped {
+            inner = match deadline {
+                Some(deadline) => {
+                    let current = Instant::now();
+
+                    if current >= deadline {
+                        return false;
+                    }
+
+                    self.worker_stopped
+                        .wait_timeout(inner, deadline - current)
+                        .unwrap()
+                        .0
+                }
+                None => self.worker_stopped.wait(inner).unwrap(),
+            };
+        }
+
+        true
+    }
+}
@@ -0,0 +1,92 @@
+use crate::config::{Clean, Project, Workspace};
+use crate::fs::{clear_dir, lock_workspace, workspace_path, FileLock};
+use crate::user::Credentials;
+use std::fs::DirBuilder;
+use std::io;
+use std::path::{Path, PathBuf};
+use tempdir::TempDir;
+
+///
+/// The working directory of a job.
+///
+#[derive(Debug)]
+pub(crate) enum JobWorkspace {
+    Temp(TempDir),
+    Persistent {
+        path: PathBuf,
+        // the lock is held as long as the job runs (and is `None` for dry runs)
+        _lock: Option<FileLock>,
+    },
+}
+
+fn persistent_path(project_name: &str, project: &Project) -> Option<PathBuf> {
+    match project.workspace {
+        Workspace::Temp => None,
+        Workspace::Persistent => Some(workspace_path(project_name)),
+        Workspace::Path(ref path) => Some(path.clone()),
+    }
+}
+
+impl JobWorkspace {
+    ///
+    /// Creates or locks the workspace of a project for a job.
+    /// A persistent workspace is emptied first if the project is configured to always clean it.
+    /// If the project runs as a different user, the workspace is handed over to that user.
+    ///
+    pub(crate) fn acquire(
+        project_name: &str,
+        project: &Project,
+        credentials: Option<&Credentials>,
+    ) -> io::Result<Self> {
+        let workspace = match persistent_path(project_name, project) {
+            Some(path) => {
+                let lock = lock_workspace(project_name)?;
+
+                DirBuilder::new().recursive(true).create(&path)?;
+
+                if project.clean == Clean::Always {
+                    clear_dir(&path)?;
+                }
+
+                JobWorkspace::Persistent {
+                    path,
+                    _lock: Some(lock),
+                }
+            }
+            None => JobWorkspace::Temp(TempDir::new("toby-job")?),
+        };
+
+        if let Some(credentials) = credentials {
+            credentials.chown(workspace.path())?;
+        }
+
+        Ok(workspace)
+    }
+
+    ///
+    /// Determines the workspace of a project without locking or modifying it.
+    ///
+    pub(crate) fn preview(project_name: &str, project: &Project) -> io::Result<Self> {
+        match persistent_path(project_name, project) {
+            Some(path) => Ok(JobWorkspace::Persistent { path, _lock: None }),
+            None => TempDir::new("toby-job").map(JobWorkspace::Temp),
+        }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        match *self {
+            JobWorkspace::Temp(ref dir) => dir.path(),
+            JobWorkspace::Persistent { ref path, .. } => path,
+        }
+    }
+
+    ///
+    /// Empties a persistent workspace. Temporary workspaces are deleted after the job anyway.
+    ///
+    pub(crate) fn clean(&self) -> io::Result<()> {
+        match *self {
+            JobWorkspace::Temp(..) => Ok(()),
+            JobWorkspace::Persistent { ref path, .. } => clear_dir(path),
+        }
+    }
+}